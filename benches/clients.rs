@@ -0,0 +1,97 @@
+//! Compares building a resolver client for every lookup, which is what `enumerate_ns` used to
+//! do, with sharing one long-lived client per nameserver. Both resolve the same hosts against a
+//! local nameserver stand-in that answers every A query, so the numbers only measure the
+//! clients. Every host is looked up once, so neither design gets to answer from its cache.
+//!
+//! Run with `cargo bench --bench clients`.
+use std::net::{Ipv4Addr, SocketAddr};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use tokio1::net::UdpSocket;
+use trust_dns_proto::op::{Message, MessageType};
+use trust_dns_proto::rr::{Name, RData, Record};
+use trust_dns_resolver::config::{NameServerConfigGroup, ResolverConfig, ResolverOpts};
+use trust_dns_resolver::TokioAsyncResolver;
+
+const HOSTS: usize = 20_000;
+
+/// Answers every query with a single A record.
+async fn serve(socket: UdpSocket) {
+    let mut buf = [0; 512];
+    loop {
+        let (len, from) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(_) => continue,
+        };
+        let mut message = match Message::from_vec(&buf[..len]) {
+            Ok(message) => message,
+            Err(_) => continue,
+        };
+        let answers: Vec<Record> = message
+            .queries()
+            .iter()
+            .map(|q| {
+                Record::from_rdata(q.name().clone(), 300, RData::A(Ipv4Addr::new(192, 0, 2, 1)))
+            })
+            .collect();
+        message
+            .set_message_type(MessageType::Response)
+            .set_recursion_available(true)
+            .insert_answers(answers);
+        if let Ok(bytes) = message.to_vec() {
+            let _ = socket.send_to(&bytes, from).await;
+        }
+    }
+}
+
+fn client(nameserver: SocketAddr) -> TokioAsyncResolver {
+    let group = NameServerConfigGroup::from_ips_clear(&[nameserver.ip()], nameserver.port(), false);
+    TokioAsyncResolver::tokio(
+        ResolverConfig::from_parts(None, vec![], group),
+        ResolverOpts::default(),
+    )
+    .expect("error building resolver")
+}
+
+fn host(i: usize) -> Name {
+    Name::from_str(&format!("host{}.example.com.", i)).unwrap()
+}
+
+fn report(name: &str, elapsed: Duration) {
+    println!(
+        "{:<10} {:>8.1?} total, {:>10.0} lookups/s",
+        name,
+        elapsed,
+        HOSTS as f64 / elapsed.as_secs_f64()
+    );
+}
+
+fn main() {
+    let runtime = tokio1::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("error building runtime");
+
+    runtime.block_on(async {
+        let socket = UdpSocket::bind(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)))
+            .await
+            .expect("error binding the nameserver");
+        let nameserver = socket.local_addr().unwrap();
+        tokio1::spawn(serve(socket));
+
+        let start = Instant::now();
+        for i in 0..HOSTS {
+            let answer = client(nameserver).ipv4_lookup(host(i)).await;
+            assert!(answer.is_ok(), "lookup failed: {:?}", answer);
+        }
+        report("per lookup", start.elapsed());
+
+        let start = Instant::now();
+        let shared = client(nameserver);
+        for i in 0..HOSTS {
+            let answer = shared.ipv4_lookup(host(i)).await;
+            assert!(answer.is_ok(), "lookup failed: {:?}", answer);
+        }
+        report("shared", start.elapsed());
+    });
+}
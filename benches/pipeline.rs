@@ -0,0 +1,162 @@
+//! Runs `ree` over a large synthetic list of hosts against a local nameserver stand-in, and
+//! reports the wall time, the cpu time and the peak memory of the run. It's how the pipeline
+//! between the lookups and the results is measured, e.g. before and after changing it.
+//!
+//! The stand-in binds an unused port on 127.0.0.1, which goes in the resolvers file along with
+//! the address. `ree` runs with its default flags, so the wildcard detection is measured too: the
+//! stand-in only answers the generated hosts, every other name doesn't exist. The cpu time and
+//! the memory come from `getrusage`, so it only runs on unix.
+//!
+//! Run with `cargo bench --bench pipeline`. `REE_BENCH_HOSTS` sets the number of hosts (150000 by
+//! default), `REE_BENCH_CONCURRENCY` the concurrency (1000 by default) and `REE_BENCH_BIN` runs
+//! another build of `ree` instead of this one, to compare the two.
+#[cfg(unix)]
+use std::fs;
+#[cfg(unix)]
+use std::net::{Ipv4Addr, UdpSocket};
+#[cfg(unix)]
+use std::process::{Command, Stdio};
+#[cfg(unix)]
+use std::str::FromStr;
+#[cfg(unix)]
+use std::time::{Duration, Instant};
+#[cfg(unix)]
+use trust_dns_proto::op::{Message, MessageType, ResponseCode};
+#[cfg(unix)]
+use trust_dns_proto::rr::{Name, RData, Record, RecordType};
+
+#[cfg(unix)]
+const THREADS: usize = 4;
+
+/// Whether `name` is one of the generated `host<N>.example.com` hosts.
+#[cfg(unix)]
+fn generated(name: &Name) -> bool {
+    let label = name.iter().next().map(String::from_utf8_lossy);
+    name.num_labels() == 3
+        && label.is_some_and(|label| {
+            let number = label.strip_prefix("host").unwrap_or_default();
+            !number.is_empty() && number.bytes().all(|b| b.is_ascii_digit())
+        })
+}
+
+/// Answers the A queries for the generated hosts with a single record and their other queries
+/// without any. Every other name doesn't exist, like the wildcard canaries.
+#[cfg(unix)]
+fn serve(socket: UdpSocket) {
+    let mut buf = [0; 512];
+    loop {
+        let (len, from) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(_) => continue,
+        };
+        let mut message = match Message::from_vec(&buf[..len]) {
+            Ok(message) => message,
+            Err(_) => continue,
+        };
+        let exists = message.queries().iter().all(|q| generated(q.name()));
+        let answers: Vec<Record> = message
+            .queries()
+            .iter()
+            .filter(|q| exists && q.query_type() == RecordType::A)
+            .map(|q| {
+                Record::from_rdata(q.name().clone(), 300, RData::A(Ipv4Addr::new(10, 0, 0, 1)))
+            })
+            .collect();
+        let rcode = if exists {
+            ResponseCode::NoError
+        } else {
+            ResponseCode::NXDomain
+        };
+        message
+            .set_message_type(MessageType::Response)
+            .set_recursion_available(true)
+            .set_response_code(rcode)
+            .insert_answers(answers);
+        if let Ok(bytes) = message.to_vec() {
+            let _ = socket.send_to(&bytes, from);
+        }
+    }
+}
+
+#[cfg(unix)]
+fn setting(name: &str, default: usize) -> usize {
+    std::env::var(name)
+        .ok()
+        .and_then(|value| usize::from_str(&value).ok())
+        .unwrap_or(default)
+}
+
+/// The cpu time and the peak memory of the children that were waited for.
+#[cfg(unix)]
+fn usage() -> (Duration, Duration, i64) {
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    unsafe { libc::getrusage(libc::RUSAGE_CHILDREN, &mut usage) };
+    let time = |t: libc::timeval| {
+        Duration::from_secs(t.tv_sec as u64) + Duration::from_micros(t.tv_usec as u64)
+    };
+    (time(usage.ru_utime), time(usage.ru_stime), usage.ru_maxrss)
+}
+
+#[cfg(not(unix))]
+fn main() {
+    eprintln!("the pipeline bench only runs on unix");
+}
+
+#[cfg(unix)]
+fn main() {
+    let hosts = setting("REE_BENCH_HOSTS", 150_000);
+    let concurrency = setting("REE_BENCH_CONCURRENCY", 1000);
+
+    let socket = UdpSocket::bind("127.0.0.1:0").expect("error binding the stand-in");
+    let nameserver = socket
+        .local_addr()
+        .expect("error reading the stand-in's address");
+    for _ in 0..THREADS {
+        let socket = socket
+            .try_clone()
+            .expect("error cloning the stand-in socket");
+        std::thread::spawn(move || serve(socket));
+    }
+
+    let dir = std::env::temp_dir().join(format!("ree-bench-{}", std::process::id()));
+    fs::create_dir_all(&dir).expect("error creating the bench directory");
+    let hosts_path = dir.join("hosts.txt");
+    let list: Vec<String> = (0..hosts)
+        .map(|i| format!("host{}.example.com", i))
+        .collect();
+    fs::write(&hosts_path, list.join("\n") + "\n").expect("error writing the hosts");
+    let resolvers_path = dir.join("resolvers.txt");
+    fs::write(&resolvers_path, format!("{}\n", nameserver)).expect("error writing the resolvers");
+
+    let start = Instant::now();
+    let bin = std::env::var("REE_BENCH_BIN").unwrap_or_else(|_| env!("CARGO_BIN_EXE_ree").into());
+    let status = Command::new(&bin)
+        .arg("-i")
+        .arg(&hosts_path)
+        .arg("-r")
+        .arg(&resolvers_path)
+        .arg("-c")
+        .arg(concurrency.to_string())
+        .arg("-o")
+        .arg(dir.join("results"))
+        .stdout(Stdio::null())
+        .status()
+        .expect("error running ree");
+    let wall = start.elapsed();
+    assert!(status.success(), "ree exited with {}", status);
+
+    let (user, sys, maxrss) = usage();
+    let written = fs::metadata(dir.join("results.json")).map_or(0, |m| m.len());
+    println!(
+        "{} hosts, -c {}: wall {:.2?}, user {:.2?}, sys {:.2?}, max rss {} MB, {} bytes written",
+        hosts,
+        concurrency,
+        wall,
+        user,
+        sys,
+        maxrss / 1024,
+        written
+    );
+    println!("{:.0} hosts/s", hosts as f64 / wall.as_secs_f64());
+    let _ = fs::remove_dir_all(&dir);
+}
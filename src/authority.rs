@@ -0,0 +1,218 @@
+use crate::clients::Client;
+use crate::data::ResolveResponse;
+use crate::engine::RawEngine;
+use crate::suffix;
+use crate::wire;
+use futures::future::{BoxFuture, FutureExt, Shared};
+use ipnet::IpNet;
+use std::collections::{HashMap, VecDeque};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::info;
+use trust_dns_resolver::error::ResolveError;
+
+/// Finds the authoritative nameservers of the zone each host is in. The nameservers of every zone
+/// are only looked up once, since most hosts share their zone with plenty of others. Hosts that
+/// miss the cache at the same time wait on the same lookup instead of each sending their own.
+pub(crate) struct Authorities {
+    resolver: Client,
+    zones: Mutex<HashMap<String, Servers>>,
+}
+
+/// A lookup of the nameservers of a zone, shared by everyone waiting on it.
+type Servers = Shared<BoxFuture<'static, Arc<Vec<IpAddr>>>>;
+
+impl Authorities {
+    pub(crate) fn new(resolver: Client) -> Self {
+        Self {
+            resolver,
+            zones: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the nameservers of the closest zone `host` is in.
+    pub(crate) async fn servers(&self, host: &str) -> Arc<Vec<IpAddr>> {
+        match self.zone(host).await {
+            Some((_, servers)) => servers,
+            None => Arc::new(Vec::new()),
+        }
+    }
+
+    /// Returns the closest zone `host` is in and its nameservers, by checking each of its parents
+    /// for NS records. Most hosts aren't zones of their own, so the host itself is only checked
+    /// when it's a registrable domain, like `example.com`, which always is.
+    pub(crate) async fn zone(&self, host: &str) -> Option<(String, Arc<Vec<IpAddr>>)> {
+        let host = host.trim_end_matches('.');
+        let parents = host.match_indices('.').map(|(i, _)| &host[i + 1..]);
+        let zones: Vec<&str> = match parents.clone().next() {
+            Some(parent) if suffix::is_public_suffix(parent) => {
+                std::iter::once(host).chain(parents).collect()
+            }
+            _ => parents.collect(),
+        };
+
+        for zone in zones {
+            let servers = self.lookup(zone).await;
+            if !servers.is_empty() {
+                info!("{} is in the zone {}", host, zone);
+                return Some((zone.to_owned(), servers));
+            }
+        }
+        None
+    }
+
+    /// Returns the nameservers of `zone`, starting their lookup if nobody has yet. Zones without
+    /// any are cached too, so they're only looked up once.
+    async fn lookup(&self, zone: &str) -> Arc<Vec<IpAddr>> {
+        let servers = self
+            .zones
+            .lock()
+            .expect("zones lock poisoned")
+            .entry(zone.to_owned())
+            .or_insert_with(|| {
+                let (resolver, zone) = (self.resolver.clone(), zone.to_owned());
+                async move { Arc::new(nameservers(&resolver, &zone).await.unwrap_or_default()) }
+                    .boxed()
+                    .shared()
+            })
+            .clone();
+        servers.await
+    }
+}
+
+/// Looks up the NS records of `zone` and returns the addresses of those nameservers. Only NS
+/// records owned by the zone itself are used, so a CNAME at the name can't send us to the
+/// nameservers of another zone.
+pub(crate) async fn nameservers(
+    resolver: &Client,
+    zone: &str,
+) -> Result<Vec<IpAddr>, ResolveError> {
+    let zone = format!("{}.", zone.trim_end_matches('.'));
+    let ns = resolver.ns_lookup(zone.clone()).await?;
+    let lookups = ns
+        .as_lookup()
+        .record_iter()
+        .filter(|record| record.name().to_utf8().eq_ignore_ascii_case(&zone))
+        .filter_map(|record| record.rdata().as_ns().cloned())
+        .map(|name| resolver.lookup_ip(name));
+
+    let addresses = futures::future::join_all(lookups)
+        .await
+        .into_iter()
+        .filter_map(Result::ok)
+        .flat_map(|lookup| lookup.iter().collect::<Vec<_>>())
+        .collect();
+    Ok(addresses)
+}
+
+/// The nameservers to send the queries about `zone` to: the `given` ones, or the authoritative
+/// nameservers of the zone when none were given.
+pub(crate) async fn zone_servers(
+    resolver: &Client,
+    zone: &str,
+    given: &[SocketAddr],
+) -> Result<Vec<SocketAddr>, ResolveError> {
+    if !given.is_empty() {
+        return Ok(given.to_vec());
+    }
+    let ips = nameservers(resolver, zone).await?;
+    Ok(ips.into_iter().map(|ip| SocketAddr::new(ip, 53)).collect())
+}
+
+/// Asks the authoritative `nameserver` for the A, AAAA and CNAME records of `host` without
+/// recursion, so the answers are exactly what that nameserver serves. The queries go through the
+/// raw `engine` when it's in use. Every response is tagged with the nameserver it came from, and
+/// they're returned along with the number of queries sent.
+pub(crate) async fn query(
+    nameserver: IpAddr,
+    host: &str,
+    subnet: Option<&IpNet>,
+    wait: Duration,
+    engine: Option<&RawEngine>,
+) -> (VecDeque<ResolveResponse>, usize) {
+    let server = SocketAddr::new(nameserver, 53);
+    let (mut responses, sent) = match engine {
+        Some(engine) => engine.lookup(server, host, false, subnet).await,
+        None => wire::lookup(server, host, false, subnet, wait).await,
+    };
+    for response in responses.iter_mut() {
+        response.set_authority(nameserver);
+    }
+    (responses, sent)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stub;
+    use trust_dns_proto::op::ResponseCode;
+    use trust_dns_proto::rr::{Name, RData, Record, RecordType};
+    use trust_dns_resolver::config::ResolverOpts;
+
+    /// Serves the NS records of `example.test`, and keeps the names the NS queries were for.
+    fn authorities() -> (Authorities, Arc<Mutex<Vec<String>>>) {
+        let asked = Arc::new(Mutex::new(Vec::new()));
+        let seen = Arc::clone(&asked);
+        let addr = stub::nameserver("example.test.", move |query: &Name, kind| {
+            let host = query.to_utf8();
+            match (host.as_str(), kind) {
+                (_, RecordType::NS) => {
+                    seen.lock().unwrap().push(host.clone());
+                    if host == "example.test." {
+                        let ns = RData::NS(stub::name("ns.example.test."));
+                        let record = Record::from_rdata(query.clone(), 300, ns);
+                        return (ResponseCode::NoError, vec![record]);
+                    }
+                    (ResponseCode::NXDomain, vec![])
+                }
+                ("ns.example.test.", RecordType::A) => {
+                    (ResponseCode::NoError, vec![stub::a(query, [192, 0, 2, 53])])
+                }
+                _ => (ResponseCode::NXDomain, vec![]),
+            }
+        });
+        // The library's own cache would hide the queries we're counting.
+        let options = ResolverOpts {
+            cache_size: 0,
+            ..ResolverOpts::default()
+        };
+        (
+            Authorities::new(Client::new(stub::config(addr), options)),
+            asked,
+        )
+    }
+
+    #[tokio::test]
+    async fn hosts_in_the_same_zone_share_one_lookup() {
+        let (authorities, asked) = authorities();
+        let hosts: Vec<String> = (0..16).map(|i| format!("h{}.example.test", i)).collect();
+        let zones =
+            futures::future::join_all(hosts.iter().map(|host| authorities.zone(host))).await;
+
+        let servers = vec!["192.0.2.53".parse::<IpAddr>().unwrap()];
+        for zone in zones {
+            assert_eq!(
+                zone,
+                Some(("example.test".to_owned(), Arc::new(servers.clone())))
+            );
+        }
+        // The hosts themselves aren't zones, so only their parent was asked about.
+        assert_eq!(*asked.lock().unwrap(), vec!["example.test.".to_owned()]);
+    }
+
+    #[tokio::test]
+    async fn registrable_domains_are_their_own_zone() {
+        let (authorities, asked) = authorities();
+        let (zone, _) = authorities.zone("example.test.").await.unwrap();
+        assert_eq!(zone, "example.test");
+
+        assert!(authorities.zone("missing.test").await.is_none());
+        authorities.zone("missing.test").await;
+        // The negative answers are cached too.
+        assert_eq!(
+            *asked.lock().unwrap(),
+            vec!["example.test.", "missing.test.", "test."]
+        );
+    }
+}
@@ -0,0 +1,226 @@
+use crate::data::ResolveResponse;
+use crate::wire;
+use crate::{ReeError, Result};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+use tracing::{info, warn};
+use trust_dns_proto::op::{Message, ResponseCode};
+use trust_dns_proto::rr::{Record, RecordType};
+
+/// Attempts a zone transfer of `zone` from the nameserver at `server`, and returns every record
+/// that was transferred. Each read from the server is limited by `wait`, so a server that stops
+/// answering part way through the transfer can't hold us up forever.
+pub(crate) async fn transfer(
+    server: SocketAddr,
+    zone: &str,
+    wait: Duration,
+) -> Result<Vec<Record>> {
+    let request = wire::query_message(zone, RecordType::AXFR, false)?.to_vec()?;
+
+    let mut stream = timeout(wait, TcpStream::connect(server)).await??;
+    // Messages over tcp are prefixed with their length.
+    stream
+        .write_all(&(request.len() as u16).to_be_bytes())
+        .await?;
+    stream.write_all(&request).await?;
+
+    // The transfer starts with the zone's SOA record and is finished once it's sent again.
+    let mut records = Vec::new();
+    let mut soa_count = 0;
+    while soa_count < 2 {
+        let mut len = [0u8; 2];
+        timeout(wait, stream.read_exact(&mut len)).await??;
+        let mut buf = vec![0u8; u16::from_be_bytes(len) as usize];
+        timeout(wait, stream.read_exact(&mut buf)).await??;
+
+        let mut response = Message::from_vec(&buf)?;
+        if response.response_code() != ResponseCode::NoError {
+            return Err(ReeError::Transfer(response.response_code().to_string()));
+        }
+
+        let answers = response.take_answers();
+        if answers.is_empty() {
+            return Err(ReeError::Transfer(String::from("empty transfer")));
+        }
+        for record in answers {
+            if record.record_type() == RecordType::SOA {
+                soa_count += 1;
+            }
+            records.push(record);
+        }
+    }
+
+    // The closing SOA is a repeat of the first one.
+    records.pop();
+    Ok(records)
+}
+
+/// Attempts the transfer of the zone in `query` from `server`, and converts the outcome into the
+/// responses we write out. A failed attempt becomes an `Error` with the reason it failed.
+pub(crate) async fn attempt(
+    server: SocketAddr,
+    query: Arc<String>,
+    wait: Duration,
+) -> Vec<ResolveResponse> {
+    match transfer(server, &query, wait).await {
+        Ok(records) => {
+            info!(
+                "transferred {} records of {} from {}",
+                records.len(),
+                query,
+                server
+            );
+            // Each record is its own answer, so the owner is used as the query. That way CNAMEs
+            // look like they do in the results of `resolve`.
+            records
+                .iter()
+                .map(|record| {
+                    let mut response =
+                        ResolveResponse::new(record, Arc::new(record.name().to_utf8()));
+                    response.set_authority(server.ip());
+                    response
+                })
+                .collect()
+        }
+        Err(e) => {
+            warn!("transfer of {} from {} failed: {}", query, server, e);
+            vec![ResolveResponse::Error {
+                query: query.to_string(),
+                response_code: e.to_string(),
+                authority: Some(server.ip().to_string()),
+                subnet: None,
+            }]
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::str::FromStr;
+    use tokio::net::TcpListener;
+    use trust_dns_proto::rr::rdata::{MX, SOA, TXT};
+    use trust_dns_proto::rr::{Name, RData};
+
+    fn record(name: &str, rdata: RData) -> Record {
+        Record::from_rdata(Name::from_str(name).unwrap(), 300, rdata)
+    }
+
+    fn zone() -> Vec<Record> {
+        let name = |name: &str| Name::from_str(name).unwrap();
+        let soa = SOA::new(
+            name("ns1.example.com."),
+            name("hostmaster.example.com."),
+            1,
+            3600,
+            600,
+            86400,
+            300,
+        );
+        vec![
+            record("example.com.", RData::SOA(soa)),
+            record("example.com.", RData::NS(name("ns1.example.com."))),
+            record("example.com.", RData::NS(name("ns2.example.com."))),
+            record(
+                "example.com.",
+                RData::MX(MX::new(10, name("mail.example.com."))),
+            ),
+            record(
+                "example.com.",
+                RData::TXT(TXT::new(vec!["v=spf1 -all".to_owned()])),
+            ),
+            record("www.example.com.", RData::A(Ipv4Addr::new(192, 0, 2, 1))),
+        ]
+    }
+
+    /// An authoritative nameserver that answers one transfer with `code`, sending the zone's
+    /// records over two messages when it allows it.
+    async fn nameserver(code: ResponseCode) -> SocketAddr {
+        let mut listener = TcpListener::bind(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)))
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut len = [0u8; 2];
+            stream.read_exact(&mut len).await.unwrap();
+            let mut buf = vec![0u8; u16::from_be_bytes(len) as usize];
+            stream.read_exact(&mut buf).await.unwrap();
+            let request = Message::from_vec(&buf).unwrap();
+
+            let mut records = zone();
+            // The transfer ends with the SOA it started with.
+            records.push(records[0].clone());
+            let messages = match code {
+                ResponseCode::NoError => vec![records[..3].to_vec(), records[3..].to_vec()],
+                _ => vec![Vec::new()],
+            };
+            for answers in messages {
+                let mut response = Message::new();
+                response
+                    .set_id(request.id())
+                    .set_response_code(code)
+                    .insert_answers(answers);
+                let response = response.to_vec().unwrap();
+                stream
+                    .write_all(&(response.len() as u16).to_be_bytes())
+                    .await
+                    .unwrap();
+                stream.write_all(&response).await.unwrap();
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn transfers_keep_the_data_of_every_record() {
+        let server = nameserver(ResponseCode::NoError).await;
+        let query = Arc::new("example.com.".to_owned());
+        let responses = attempt(server, query, Duration::from_secs(5)).await;
+        assert_eq!(responses.len(), zone().len());
+
+        let mut rows: Vec<(String, String, String)> = responses
+            .iter()
+            .map(|response| {
+                let (query, kind, value, authority, _) = response.key();
+                assert_eq!(authority, "127.0.0.1");
+                (query, kind, value)
+            })
+            .collect();
+        rows.sort();
+        let row = |query: &str, kind: &str, value: &str| {
+            (query.to_owned(), kind.to_owned(), value.to_owned())
+        };
+        assert!(rows.contains(&row("example.com.", "NS", "ns1.example.com.")));
+        assert!(rows.contains(&row("example.com.", "NS", "ns2.example.com.")));
+        assert!(rows.contains(&row("example.com.", "MX", "10 mail.example.com.")));
+        assert!(rows.contains(&row("example.com.", "TXT", "v=spf1 -all")));
+        assert!(rows.contains(&row("www.example.com.", "A", "192.0.2.1")));
+    }
+
+    #[tokio::test]
+    async fn refused_transfers_name_the_nameserver() {
+        let server = nameserver(ResponseCode::Refused).await;
+        let query = Arc::new("example.com.".to_owned());
+        let responses = attempt(server, query, Duration::from_secs(5)).await;
+        match responses.as_slice() {
+            [ResolveResponse::Error {
+                response_code,
+                authority,
+                ..
+            }] => {
+                assert_eq!(response_code, &ResponseCode::Refused.to_string());
+                assert_eq!(
+                    authority.as_deref(),
+                    Some(IpAddr::from(Ipv4Addr::LOCALHOST).to_string().as_str())
+                );
+            }
+            other => panic!("expected a single error: {:?}", other),
+        }
+    }
+}
@@ -1,10 +1,13 @@
-use clap::{App, Arg};
-use reesolve::Input;
-use reesolve::Resolver;
+use clap::{App, Arg, SubCommand};
+use ipnet::IpNet;
 use reesolve::Result;
+use reesolve::{CloudRanges, Fingerprints, Permutator, Resolver};
+use reesolve::{Input, Wordlist};
+use std::net::{IpAddr, SocketAddr};
+use std::ops::RangeInclusive;
 use std::path::{Path, PathBuf};
 
-fn create_clap_app(version: &str) -> clap::App {
+fn create_clap_app(version: &str) -> clap::App<'_, '_> {
     App::new("reesolve")
         .version(version)
         .about("A DNS resolver written in Rust")
@@ -14,6 +17,7 @@ fn create_clap_app(version: &str) -> clap::App {
                 .help("ree -i <hosts.txt>")
                 .short("i")
                 .long("input-file")
+                .global(true)
                 .takes_value(true),
         )
         .arg(
@@ -21,6 +25,7 @@ fn create_clap_app(version: &str) -> clap::App {
                 .help("ree -r <resolvers.txt>\nThe default list of resolvers used is Google & CloudFlare.")
                 .short("r")
                 .long("resolvers")
+                .global(true)
                 .takes_value(true),
         )
         .arg(
@@ -29,6 +34,7 @@ fn create_clap_app(version: &str) -> clap::App {
                 .short("c")
                 .long("concurrency")
                 .default_value("320")
+                .global(true)
                 .takes_value(true),
         )
         .arg(
@@ -36,6 +42,7 @@ fn create_clap_app(version: &str) -> clap::App {
                 .help("ree -i hosts.txt -v info")
                 .short("v")
                 .long("verbosity")
+                .global(true)
                 .takes_value(true),
         )
         .arg(
@@ -44,6 +51,7 @@ fn create_clap_app(version: &str) -> clap::App {
                 .short("t")
                 .long("timeout")
                 .default_value("5")
+                .global(true)
                 .takes_value(true),
         )
         //TODO: if the stdout argument is used, we shouldn't use output or output_format
@@ -55,6 +63,7 @@ fn create_clap_app(version: &str) -> clap::App {
                 .short("o")
                 .long("output")
                 .default_value("records")
+                .global(true)
                 .takes_value(true),
         )
         .arg(
@@ -63,12 +72,186 @@ fn create_clap_app(version: &str) -> clap::App {
                 .short("-f")
                 .long("output-format")
                 .default_value("json")
+                .global(true)
                 .takes_value(true),
         )
         .arg(
             Arg::with_name("stdout")
                 .help("ree -i hosts.txt --stdout")
                 .long("--stdout")
+                .global(true)
+        )
+        .arg(
+            Arg::with_name("permute")
+                .help("ree -i hosts.txt --permute\nResolves permutations of the resolved names until no new names are found.")
+                .long("permute")
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("permute-words")
+                .help("ree -i hosts.txt --permute-words <words.txt>\nAdds words to the default permutation words, implies --permute.")
+                .long("permute-words")
+                .global(true)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("takeover")
+                .help("ree -i hosts.txt --takeover\nFlags hosts with dangling CNAMEs and the services their targets belong to.")
+                .long("takeover")
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("fingerprints")
+                .help("ree -i hosts.txt --fingerprints <fingerprints.txt>\nAdds `*.service.com,Service` lines to the default fingerprints, implies --takeover.")
+                .long("fingerprints")
+                .global(true)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("cloud-ranges")
+                .help("ree -i hosts.txt --cloud-ranges ip-ranges.json --cloud-ranges cloud.json\nAdds the ranges in the json files published by AWS, Google Cloud and Azure, or `cidr,provider,region` lines, to the bundled cloud ranges.")
+                .long("cloud-ranges")
+                .multiple(true)
+                .number_of_values(1)
+                .global(true)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("wildcard")
+                .help("ree -i hosts.txt --wildcard drop\nkeep skips wildcard detection, flag marks wildcards, drop discards them and only keeps just the wildcards.")
+                .long("wildcard")
+                .possible_values(&["keep", "flag", "drop", "only"])
+                .default_value("flag")
+                .global(true)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("authoritative")
+                .help("ree -i hosts.txt --authoritative\nQueries the authoritative nameservers of each host directly instead of the resolvers.")
+                .long("authoritative")
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("engine")
+                .help("ree -i hosts.txt --engine raw\nThe engine used to send the queries, raw is much faster for large lists of hosts.")
+                .long("engine")
+                .possible_values(&["resolver", "raw"])
+                .default_value("resolver")
+                .global(true)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("bind")
+                .help("ree -i hosts.txt --engine raw --bind 10.0.0.5\nThe local address the queries are sent from, needs --engine raw.")
+                .long("bind")
+                .global(true)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("source-ports")
+                .help("ree -i hosts.txt --engine raw --source-ports 40000-50000\nThe range of source ports the queries are sent from, needs --engine raw.")
+                .long("source-ports")
+                .global(true)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("sockets")
+                .help("ree -i hosts.txt --engine raw --sockets 32\nHow many udp sockets the queries are spread over, needs --engine raw.")
+                .long("sockets")
+                .global(true)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("subnet")
+                .help("ree -i hosts.txt --subnet 1.2.3.0/24 --subnet 2001:db8::/32\nSends the EDNS Client Subnet option with the queries, once for each subnet.")
+                .long("subnet")
+                .multiple(true)
+                .number_of_values(1)
+                .global(true)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("dnssec")
+                .help("ree -i hosts.txt --dnssec\nValidates the answers of each host with DNSSEC and reports which zones are signed.")
+                .long("dnssec")
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("state-dir")
+                .help("ree -i hosts.txt --state-dir ./state\nCheckpoints the completed hosts and their responses to the directory while running.")
+                .long("state-dir")
+                .global(true)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("metrics-addr")
+                .help("ree -i hosts.txt --metrics-addr 127.0.0.1:9100\nServes Prometheus metrics of the run on http://<addr>/metrics.")
+                .long("metrics-addr")
+                .global(true)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("resume")
+                .help("ree -i hosts.txt --state-dir ./state --resume\nResumes the run checkpointed to the state directory, skipping the hosts it completed.")
+                .long("resume")
+                .requires("state-dir")
+                .global(true),
+        )
+        .subcommand(
+            SubCommand::with_name("brute")
+                .about("Brute forces subdomains using a wordlist")
+                .usage("ree brute -d example.com -w words.txt")
+                .arg(
+                    Arg::with_name("domain")
+                        .help("ree brute -d example.com -w words.txt")
+                        .short("d")
+                        .long("domain")
+                        .required(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("wordlist")
+                        .help("ree brute -d example.com -w words.txt")
+                        .short("w")
+                        .long("wordlist")
+                        .required(true)
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("axfr")
+                .about("Attempts a zone transfer of each domain from its authoritative nameservers")
+                .usage("cat domains.txt | ree axfr")
+                .arg(
+                    Arg::with_name("nameserver")
+                        .help("ree axfr -i domains.txt -n 127.0.0.1:5353\nOnly attempt the transfers against these nameservers.")
+                        .short("n")
+                        .long("nameserver")
+                        .multiple(true)
+                        .number_of_values(1)
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("walk")
+                .about("Enumerates the names of each domain by walking its NSEC records")
+                .usage("cat domains.txt | ree walk")
+                .arg(
+                    Arg::with_name("nameserver")
+                        .help("ree walk -i domains.txt -n 127.0.0.1:5353\nOnly walk the zones using these nameservers.")
+                        .short("n")
+                        .long("nameserver")
+                        .multiple(true)
+                        .number_of_values(1)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("hashes")
+                        .help("ree walk -i domains.txt --hashes hashes.txt\nWhere to write the NSEC3 hashes of zones that can't be walked, in hashcat's format.")
+                        .long("hashes")
+                        .default_value("nsec3_hashes.txt")
+                        .takes_value(true),
+                ),
         )
 }
 
@@ -78,18 +261,44 @@ fn make_path(path: &str, format: &str) -> PathBuf {
     path.with_file_name(format!("{}.{}", file, format))
 }
 
+// Nameservers can be given with or without a port, without one port 53 is used.
+fn parse_nameserver(value: &str) -> SocketAddr {
+    value
+        .parse::<SocketAddr>()
+        .or_else(|_| value.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, 53)))
+        .expect("invalid nameserver address")
+}
+
+// Subnets can be given without a prefix length, in which case they're a single address.
+fn parse_subnet(value: &str) -> IpNet {
+    value
+        .parse::<IpNet>()
+        .or_else(|_| value.parse::<IpAddr>().map(IpNet::from))
+        .expect("invalid subnet")
+}
+
+// A range of ports like `40000-50000`, or a single port.
+fn parse_ports(value: &str) -> RangeInclusive<u16> {
+    let (start, end) = value.split_once('-').unwrap_or((value, value));
+    let start: u16 = start.trim().parse().expect("invalid source port");
+    let end: u16 = end.trim().parse().expect("invalid source port");
+    assert!(start <= end, "invalid source port range");
+    start..=end
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     let args = create_clap_app(env!("CARGO_PKG_VERSION"));
-    let matches = args.get_matches();
+    let app_matches = args.get_matches();
+    // Global arguments are only propagated down into the subcommand's matches.
+    let (subcommand, subcommand_matches) = app_matches.subcommand();
+    let matches = subcommand_matches.unwrap_or(&app_matches);
+
     let concurrency: usize = matches.value_of("concurrency").unwrap().parse()?;
     let timeout: u64 = matches.value_of("timeout").unwrap().parse()?;
-    let input_file = matches.value_of("input-file");
     let output_format = matches.value_of("output-format").unwrap();
     let stdout = matches.is_present("stdout");
-
     let output_path = make_path(matches.value_of("output").unwrap(), output_format);
-    let targets = Input::new(input_file).hosts();
 
     if matches.is_present("verbosity") {
         let builder = tracing_subscriber::fmt()
@@ -100,19 +309,89 @@ async fn main() -> Result<()> {
     }
 
     // if the user specified a list of resolvers, use them.
-    let ree = Resolver::default();
-    if matches.is_present("resolvers") {
-        let resolvers = matches.value_of("resolvers").unwrap();
-        ree.load_resolvers(resolvers)
-            .timeout(timeout)
-            .output(output_format, output_path, stdout)
-            .resolve(targets, concurrency)
-            .await?;
-    } else {
-        ree.timeout(timeout)
-            .output(output_format, output_path, stdout)
-            .resolve(targets, concurrency)
-            .await?;
+    let mut ree = Resolver::default();
+    if let Some(resolvers) = matches.value_of("resolvers") {
+        ree = ree.load_resolvers(resolvers);
+    }
+    if matches.is_present("permute") || matches.is_present("permute-words") {
+        let mut permutator = Permutator::default();
+        if let Some(words) = matches.value_of("permute-words") {
+            permutator = permutator.load_words(words);
+        }
+        ree = ree.permute(permutator);
+    }
+    if matches.is_present("takeover") || matches.is_present("fingerprints") {
+        let mut fingerprints = Fingerprints::default();
+        if let Some(path) = matches.value_of("fingerprints") {
+            fingerprints = fingerprints.load(path);
+        }
+        ree = ree.takeover(fingerprints);
+    }
+    if let Some(paths) = matches.values_of("cloud-ranges") {
+        ree = ree.cloud_ranges(paths.fold(CloudRanges::default(), CloudRanges::load));
+    }
+    if matches.is_present("authoritative") {
+        ree = ree.authoritative();
+    }
+    if matches.is_present("dnssec") {
+        ree = ree.dnssec();
+    }
+    if let Some(address) = matches.value_of("bind") {
+        ree = ree.bind_address(address.parse().expect("invalid bind address"));
+    }
+    if let Some(ports) = matches.value_of("source-ports") {
+        ree = ree.source_ports(parse_ports(ports));
+    }
+    if let Some(sockets) = matches.value_of("sockets") {
+        ree = ree.sockets(sockets.parse()?);
+    }
+    if let Some(values) = matches.values_of("subnet") {
+        ree = ree.subnets(values.map(parse_subnet).collect());
+    }
+    if let Some(dir) = matches.value_of("state-dir") {
+        ree = ree.state_dir(PathBuf::from(dir));
+    }
+    if matches.is_present("resume") {
+        ree = ree.resume();
+    }
+    if let Some(addr) = matches.value_of("metrics-addr") {
+        ree = ree.metrics_addr(addr.parse().expect("invalid metrics address"));
+    }
+    let ree = ree
+        .timeout(timeout)
+        .wildcard(matches.value_of("wildcard").unwrap())
+        .engine(matches.value_of("engine").unwrap())
+        .output(output_format, output_path, stdout);
+
+    match subcommand {
+        "brute" => {
+            let words = Wordlist::new(
+                matches.value_of("wordlist").unwrap(),
+                matches.value_of("domain").unwrap(),
+            );
+            ree.brute(words, concurrency).await?;
+        }
+        "axfr" => {
+            let domains = Input::new(matches.value_of("input-file")).hosts();
+            let nameservers = matches
+                .values_of("nameserver")
+                .map(|values| values.map(parse_nameserver).collect())
+                .unwrap_or_default();
+            ree.axfr(domains, nameservers, concurrency).await?;
+        }
+        "walk" => {
+            let domains = Input::new(matches.value_of("input-file")).hosts();
+            let nameservers = matches
+                .values_of("nameserver")
+                .map(|values| values.map(parse_nameserver).collect())
+                .unwrap_or_default();
+            let hashes = PathBuf::from(matches.value_of("hashes").unwrap());
+            ree.walk(domains, nameservers, hashes, concurrency).await?;
+        }
+        _ => {
+            let targets = Input::new(matches.value_of("input-file")).hosts();
+            ree.resolve(targets, concurrency).await?;
+        }
     }
 
     Ok(())
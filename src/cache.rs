@@ -0,0 +1,210 @@
+use crate::data::{cname_chain, normalize};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Instant;
+use trust_dns_proto::op::Query;
+use trust_dns_proto::rr::{Name, Record, RecordType};
+use trust_dns_resolver::lookup::Lookup;
+
+/// The (resolver, name, type) a lookup is stored under in the `AnswerCache`. Address lookups ask
+/// for the A and AAAA records at once, so they're stored under `A`.
+type Key = (IpAddr, String, RecordType);
+
+/// The most entries the `AnswerCache` holds. Once it's full, the expired entries are swept out, and
+/// new answers are only stored when that made room for them.
+const MAX_ENTRIES: usize = 100_000;
+
+/// Answers shared across every host, so names that many hosts point at, like the CNAME targets of
+/// a CDN, are only looked up once per resolver until their TTL runs out. Only those names are
+/// stored, the hosts themselves are unique so their own answers would never be read again. Errors
+/// are always looked up again.
+#[derive(Debug, Default)]
+pub(crate) struct AnswerCache {
+    // Every lookup reads the cache, only the misses write to it.
+    entries: RwLock<HashMap<Key, Lookup>>,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+}
+
+impl AnswerCache {
+    /// Returns the cached lookup of `name` from `resolver`, as long as it hasn't expired yet.
+    pub(crate) fn get(&self, resolver: IpAddr, name: &str, kind: RecordType) -> Option<Lookup> {
+        let key = (resolver, normalize(name), kind);
+        let entries = self.entries.read().unwrap();
+        entries
+            .get(&key)
+            .filter(|lookup| lookup.valid_until() > Instant::now())
+            .cloned()
+    }
+
+    /// Counts a lookup towards the hit rate, depending on whether it was answered from the cache.
+    pub(crate) fn record(&self, hit: bool) {
+        if hit {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Stores `lookup` under every name its CNAME chain goes through after the query, with only
+    /// the records from that name on. That way a host whose CNAME points into the chain can reuse
+    /// the rest of it.
+    pub(crate) fn insert(&self, resolver: IpAddr, kind: RecordType, lookup: &Lookup) {
+        let records: Vec<&Record> = lookup.record_iter().collect();
+        let chain = cname_chain(records.iter().copied(), &lookup.query().name().to_utf8());
+        if chain.len() == 1 {
+            return;
+        }
+
+        let mut entries = self.entries.write().unwrap();
+        let room = |entries: &HashMap<Key, Lookup>| entries.len() + chain.len() - 1 <= MAX_ENTRIES;
+        if !room(&entries) {
+            let now = Instant::now();
+            entries.retain(|_, lookup| lookup.valid_until() > now);
+            if !room(&entries) {
+                return;
+            }
+        }
+        for (i, name) in chain.iter().enumerate().skip(1) {
+            let suffix: Vec<Record> = records
+                .iter()
+                .filter(|record| chain[i..].contains(&normalize(&record.name().to_utf8())))
+                .map(|record| (*record).clone())
+                .collect();
+            let query = match Name::from_utf8(name) {
+                Ok(name) => Query::query(name, kind),
+                Err(_) => continue,
+            };
+            entries.insert(
+                (resolver, name.clone(), kind),
+                Lookup::new_with_deadline(query, Arc::from(suffix), lookup.valid_until()),
+            );
+        }
+    }
+
+    pub(crate) fn hits(&self) -> usize {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn misses(&self) -> usize {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    /// The share of lookups that were answered from the cache, as a percentage.
+    pub(crate) fn hit_rate(&self) -> f64 {
+        let total = self.hits() + self.misses();
+        if total == 0 {
+            return 0.0;
+        }
+        self.hits() as f64 / total as f64 * 100.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use std::str::FromStr;
+    use std::time::Duration;
+    use trust_dns_proto::rr::RData;
+
+    const RESOLVER: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+    fn name(name: &str) -> Name {
+        Name::from_str(name).unwrap()
+    }
+
+    /// `host` going through `cname` to `192.0.2.1`, valid for `ttl`.
+    fn lookup(host: &str, cname: &str, ttl: Duration) -> Lookup {
+        let records = vec![
+            Record::from_rdata(name(host), 300, RData::CNAME(name(cname))),
+            Record::from_rdata(name(cname), 300, RData::A(Ipv4Addr::new(192, 0, 2, 1))),
+        ];
+        let query = Query::query(name(host), RecordType::A);
+        Lookup::new_with_deadline(query, Arc::from(records), Instant::now() + ttl)
+    }
+
+    #[test]
+    fn only_the_cname_targets_are_stored() {
+        let cache = AnswerCache::default();
+        let ttl = Duration::from_secs(60);
+        cache.insert(
+            RESOLVER,
+            RecordType::A,
+            &lookup("a.example.com.", "cdn.net.", ttl),
+        );
+
+        assert!(cache
+            .get(RESOLVER, "a.example.com", RecordType::A)
+            .is_none());
+        let rest = cache.get(RESOLVER, "CDN.net", RecordType::A).unwrap();
+        assert_eq!(rest.query().name(), &name("cdn.net."));
+        assert_eq!(rest.record_iter().count(), 1);
+        assert!(cache.get(RESOLVER, "cdn.net", RecordType::CNAME).is_none());
+        let other = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 53));
+        assert!(cache.get(other, "cdn.net", RecordType::A).is_none());
+    }
+
+    #[test]
+    fn answers_without_a_cname_are_not_stored() {
+        let cache = AnswerCache::default();
+        let records = vec![Record::from_rdata(
+            name("a.example.com."),
+            300,
+            RData::A(Ipv4Addr::new(192, 0, 2, 1)),
+        )];
+        let query = Query::query(name("a.example.com."), RecordType::A);
+        let lookup = Lookup::new_with_deadline(
+            query,
+            Arc::from(records),
+            Instant::now() + Duration::from_secs(60),
+        );
+        cache.insert(RESOLVER, RecordType::A, &lookup);
+        assert!(cache.entries.read().unwrap().is_empty());
+    }
+
+    #[test]
+    fn expired_answers_are_not_returned() {
+        let cache = AnswerCache::default();
+        cache.insert(
+            RESOLVER,
+            RecordType::A,
+            &lookup("a.example.com.", "cdn.net.", Duration::from_millis(0)),
+        );
+        assert!(cache.get(RESOLVER, "cdn.net", RecordType::A).is_none());
+    }
+
+    #[test]
+    fn a_full_cache_sweeps_out_the_expired_answers() {
+        let cache = AnswerCache::default();
+        let expired = lookup("a.example.com.", "cdn.net.", Duration::from_millis(0));
+        let fresh = lookup("a.example.com.", "cdn.net.", Duration::from_secs(60));
+        {
+            let mut entries = cache.entries.write().unwrap();
+            for i in 0..MAX_ENTRIES {
+                let lookup = if i == 0 { &expired } else { &fresh };
+                entries.insert((RESOLVER, format!("{}.", i), RecordType::A), lookup.clone());
+            }
+        }
+
+        // Makes room by dropping the one that expired.
+        cache.insert(
+            RESOLVER,
+            RecordType::A,
+            &lookup("b.example.com.", "b.net.", Duration::from_secs(60)),
+        );
+        assert!(cache.get(RESOLVER, "b.net", RecordType::A).is_some());
+        assert_eq!(cache.entries.read().unwrap().len(), MAX_ENTRIES);
+
+        // Nothing expired is left to make room with.
+        cache.insert(
+            RESOLVER,
+            RecordType::A,
+            &lookup("c.example.com.", "c.net.", Duration::from_secs(60)),
+        );
+        assert!(cache.get(RESOLVER, "c.net", RecordType::A).is_none());
+        assert_eq!(cache.entries.read().unwrap().len(), MAX_ENTRIES);
+    }
+}
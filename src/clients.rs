@@ -0,0 +1,118 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::OnceLock;
+use tokio1::runtime::{Builder, Runtime};
+use trust_dns_proto::rr::RecordType;
+use trust_dns_proto::xfer::DnsRequestOptions;
+use trust_dns_resolver::config::{NameServerConfigGroup, ResolverConfig, ResolverOpts};
+use trust_dns_resolver::error::ResolveError;
+use trust_dns_resolver::lookup::{Lookup, NsLookup};
+use trust_dns_resolver::lookup_ip::LookupIp;
+use trust_dns_resolver::{IntoName, TokioAsyncResolver, TryParseIp};
+
+/// One long-lived client for each nameserver, shared by every host. Building a client sets up
+/// its own connection pool and cache, so building them per host threw away all of that work
+/// every time. The clients are built the first time they're needed, once the nameservers and
+/// options can no longer change.
+#[derive(Default)]
+pub(crate) struct Clients {
+    inner: OnceLock<HashMap<SocketAddr, Client>>,
+}
+
+impl Clients {
+    /// Returns the client for `nameserver`, building the clients for all of the `nameservers`
+    /// the first time it's called.
+    pub(crate) fn get(
+        &self,
+        nameservers: &[SocketAddr],
+        options: ResolverOpts,
+        nameserver: SocketAddr,
+    ) -> &Client {
+        self.inner
+            .get_or_init(|| {
+                nameservers
+                    .iter()
+                    .map(|ns| (*ns, client(*ns, options)))
+                    .collect()
+            })
+            .get(&nameserver)
+            .expect("no client for nameserver")
+    }
+}
+
+impl fmt::Debug for Clients {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let built = self.inner.get().map_or(0, HashMap::len);
+        f.debug_struct("Clients").field("built", &built).finish()
+    }
+}
+
+/// Builds a client that only sends its queries to `nameserver`.
+fn client(nameserver: SocketAddr, options: ResolverOpts) -> Client {
+    let group = NameServerConfigGroup::from_ips_clear(&[nameserver.ip()], nameserver.port(), false);
+    Client::new(ResolverConfig::from_parts(None, vec![], group), options)
+}
+
+/// A client of the resolver library. The library runs on tokio 1 while we run on tokio 0.2, so
+/// its lookups are run on a tokio 1 runtime of their own and their results are awaited from ours.
+#[derive(Clone)]
+pub(crate) struct Client {
+    inner: TokioAsyncResolver,
+}
+
+impl Client {
+    pub(crate) fn new(config: ResolverConfig, options: ResolverOpts) -> Self {
+        let inner = TokioAsyncResolver::tokio(config, options).expect("error building resolver");
+        Self { inner }
+    }
+
+    /// Looks up the A and AAAA records of `host`, following its CNAMEs.
+    pub(crate) async fn lookup_ip<N>(&self, host: N) -> Result<LookupIp, ResolveError>
+    where
+        N: IntoName + TryParseIp + Send + 'static,
+    {
+        let inner = self.inner.clone();
+        spawn(async move { inner.lookup_ip(host).await }).await
+    }
+
+    /// Looks up the `kind` records of `name`.
+    pub(crate) async fn lookup<N: IntoName>(
+        &self,
+        name: N,
+        kind: RecordType,
+        options: DnsRequestOptions,
+    ) -> Result<Lookup, ResolveError> {
+        spawn(self.inner.lookup(name, kind, options)).await
+    }
+
+    /// Looks up the NS records of `zone`.
+    pub(crate) async fn ns_lookup<N>(&self, zone: N) -> Result<NsLookup, ResolveError>
+    where
+        N: IntoName + Send + 'static,
+    {
+        let inner = self.inner.clone();
+        spawn(async move { inner.ns_lookup(zone).await }).await
+    }
+}
+
+/// Runs `future` on the tokio 1 runtime, which is started the first time it's needed.
+async fn spawn<F>(future: F) -> F::Output
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+    let runtime = RUNTIME.get_or_init(|| {
+        Builder::new_multi_thread()
+            .thread_name("ree-resolver")
+            .enable_all()
+            .build()
+            .expect("error starting the resolver runtime")
+    });
+    runtime
+        .spawn(future)
+        .await
+        .expect("resolver lookup panicked")
+}
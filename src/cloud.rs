@@ -0,0 +1,287 @@
+use crate::data::{ResolveResponse, ResultsCache};
+use ipnet::IpNet;
+use serde::Deserialize;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::net::IpAddr;
+
+/// The bundled snapshot of cloud provider ranges, see `data/cloud_ranges.csv`.
+const BUNDLED_RANGES: &str = include_str!("../data/cloud_ranges.csv");
+
+/// Address ranges owned by cloud providers, used to tag each `IpRecord` with the provider and
+/// region it points into.
+#[derive(Debug)]
+pub struct CloudRanges {
+    // Keyed by the network, so finding the most specific range containing an address takes one
+    // map lookup for each prefix length in use, instead of going through every range.
+    ranges: HashMap<IpNet, (String, String)>,
+    prefix_lens: BTreeSet<u8>,
+}
+
+impl Default for CloudRanges {
+    fn default() -> Self {
+        let mut ranges = Self {
+            ranges: HashMap::new(),
+            prefix_lens: BTreeSet::new(),
+        };
+        ranges.extend(parse_csv(BUNDLED_RANGES));
+        ranges
+    }
+}
+
+/// The range files AWS, Google Cloud and Azure publish, in the formats they publish them in.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Published {
+    /// `ip-ranges.json`
+    Aws {
+        prefixes: Vec<AwsPrefix>,
+        ipv6_prefixes: Vec<AwsPrefix>,
+    },
+    /// `cloud.json`
+    Gcp { prefixes: Vec<GcpPrefix> },
+    /// `ServiceTags_Public_*.json`
+    Azure { values: Vec<AzureTag> },
+}
+
+#[derive(Deserialize)]
+struct AwsPrefix {
+    #[serde(alias = "ipv6_prefix")]
+    ip_prefix: String,
+    region: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GcpPrefix {
+    ipv4_prefix: Option<String>,
+    ipv6_prefix: Option<String>,
+    scope: String,
+}
+
+#[derive(Deserialize)]
+struct AzureTag {
+    properties: AzureProperties,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AzureProperties {
+    region: String,
+    address_prefixes: Vec<String>,
+}
+
+impl CloudRanges {
+    /// Adds the ranges in the file at `path`, either in the same `cidr,provider,region` format as
+    /// the bundled snapshot, or one of the json files published by AWS, Google Cloud and Azure.
+    /// Ranges from the file win over the ones already loaded with the same prefix.
+    pub fn load(mut self, path: &str) -> Self {
+        let file = std::fs::read_to_string(path).expect("unable to read cloud ranges");
+        if file.trim_start().starts_with('{') {
+            let published: Published =
+                serde_json::from_str(&file).expect("unknown cloud ranges format");
+            self.extend(parse_published(published));
+        } else {
+            self.extend(parse_csv(&file));
+        }
+        self
+    }
+
+    fn extend(&mut self, ranges: Vec<(IpNet, String, String)>) {
+        for (net, provider, region) in ranges {
+            let net = net.trunc();
+            self.prefix_lens.insert(net.prefix_len());
+            self.ranges.insert(net, (provider, region));
+        }
+    }
+
+    /// Returns the provider and region of the most specific range containing `ip`.
+    fn lookup(&self, ip: &IpAddr) -> Option<(&str, &str)> {
+        self.prefix_lens
+            .iter()
+            .rev()
+            // Prefixes that are too long for the address family are errors.
+            .filter_map(|len| IpNet::new(*ip, *len).ok())
+            .find_map(|net| self.ranges.get(&net.trunc()))
+            .map(|(provider, region)| (provider.as_str(), region.as_str()))
+    }
+
+    /// Tags every `IpRecord` in the cache with the provider and region its address belongs to.
+    pub(crate) async fn tag(&self, cache: &ResultsCache) {
+        let mut lock = cache.inner.lock().await;
+        for record in lock.values_mut() {
+            if let ResolveResponse::IpRecord {
+                value: Some(ip),
+                provider,
+                region,
+                ..
+            } = record
+            {
+                if let Some((p, r)) = self.lookup(ip) {
+                    *provider = Some(p.to_owned());
+                    *region = Some(r.to_owned());
+                }
+            }
+        }
+    }
+}
+
+/// Parses `cidr,provider,region` lines, skipping comments and lines that don't parse. When a
+/// prefix is listed more than once, the first one wins.
+fn parse_csv(contents: &str) -> Vec<(IpNet, String, String)> {
+    let mut ranges = Vec::new();
+    for line in contents.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let mut fields = line.split(',').map(str::trim);
+        if let (Some(Ok(net)), Some(provider), Some(region)) = (
+            fields.next().map(str::parse::<IpNet>),
+            fields.next(),
+            fields.next(),
+        ) {
+            ranges.push((net, provider.to_owned(), region.to_owned()));
+        }
+    }
+    first_wins(ranges)
+}
+
+/// Converts a published range file into ranges. Azure lists its ranges under a tag for every
+/// region and again under global tags without one, the regional tags win.
+fn parse_published(published: Published) -> Vec<(IpNet, String, String)> {
+    let ranges: Vec<(String, &str, String)> = match published {
+        Published::Aws {
+            prefixes,
+            ipv6_prefixes,
+        } => prefixes
+            .into_iter()
+            .chain(ipv6_prefixes)
+            .map(|prefix| (prefix.ip_prefix, "aws", prefix.region))
+            .collect(),
+        Published::Gcp { prefixes } => prefixes
+            .into_iter()
+            .filter_map(|prefix| {
+                let net = prefix.ipv4_prefix.or(prefix.ipv6_prefix)?;
+                Some((net, "gcp", prefix.scope))
+            })
+            .collect(),
+        Published::Azure { mut values } => {
+            values.sort_by_key(|tag| tag.properties.region.is_empty());
+            values
+                .into_iter()
+                .flat_map(|tag| {
+                    let region = match tag.properties.region.as_str() {
+                        "" => "global".to_owned(),
+                        region => region.to_owned(),
+                    };
+                    tag.properties
+                        .address_prefixes
+                        .into_iter()
+                        .map(move |net| (net, "azure", region.clone()))
+                })
+                .collect()
+        }
+    };
+
+    first_wins(
+        ranges
+            .into_iter()
+            .filter_map(|(net, provider, region)| {
+                Some((net.parse().ok()?, provider.to_owned(), region))
+            })
+            .collect(),
+    )
+}
+
+/// Drops every range whose prefix was already listed earlier.
+fn first_wins(ranges: Vec<(IpNet, String, String)>) -> Vec<(IpNet, String, String)> {
+    let mut seen = HashSet::new();
+    ranges
+        .into_iter()
+        .filter(|(net, ..)| seen.insert(net.trunc()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ranges(published: &str) -> CloudRanges {
+        let mut ranges = CloudRanges::default();
+        ranges.extend(parse_published(serde_json::from_str(published).unwrap()));
+        ranges
+    }
+
+    fn lookup<'a>(ranges: &'a CloudRanges, ip: &str) -> Option<(&'a str, &'a str)> {
+        ranges.lookup(&ip.parse().unwrap())
+    }
+
+    #[test]
+    fn the_most_specific_range_wins() {
+        let mut ranges = CloudRanges::default();
+        ranges.extend(parse_csv(
+            "10.0.0.0/8,example,wide\n10.1.0.0/16,example,narrow\n10.1.2.0/24,example,narrowest",
+        ));
+        assert_eq!(lookup(&ranges, "10.1.2.3"), Some(("example", "narrowest")));
+        assert_eq!(lookup(&ranges, "10.1.3.3"), Some(("example", "narrow")));
+        assert_eq!(lookup(&ranges, "10.2.0.1"), Some(("example", "wide")));
+        assert_eq!(lookup(&ranges, "192.0.2.1"), None);
+    }
+
+    #[test]
+    fn loaded_ranges_win_over_the_bundled_ones() {
+        let mut ranges = CloudRanges::default();
+        let (net, ..) = &parse_csv(BUNDLED_RANGES)[0];
+        ranges.extend(parse_csv(&format!("{},mine,here", net)));
+        assert_eq!(
+            lookup(&ranges, &net.addr().to_string()),
+            Some(("mine", "here"))
+        );
+    }
+
+    #[test]
+    fn aws_ranges() {
+        let ranges = ranges(
+            r#"{"syncToken": "1", "prefixes": [
+                {"ip_prefix": "198.51.100.0/24", "region": "eu-west-3", "service": "AMAZON"},
+                {"ip_prefix": "198.51.100.0/24", "region": "eu-west-3", "service": "EC2"}
+            ], "ipv6_prefixes": [
+                {"ipv6_prefix": "2001:db8:1::/48", "region": "us-west-2", "service": "AMAZON"}
+            ]}"#,
+        );
+        assert_eq!(lookup(&ranges, "198.51.100.7"), Some(("aws", "eu-west-3")));
+        assert_eq!(lookup(&ranges, "2001:db8:1::1"), Some(("aws", "us-west-2")));
+    }
+
+    #[test]
+    fn gcp_ranges() {
+        let ranges = ranges(
+            r#"{"syncToken": "1", "prefixes": [
+                {"ipv4Prefix": "198.51.100.0/24", "service": "Google Cloud", "scope": "europe-west9"},
+                {"ipv6Prefix": "2001:db8:2::/48", "service": "Google Cloud", "scope": "us-east4"}
+            ]}"#,
+        );
+        assert_eq!(
+            lookup(&ranges, "198.51.100.7"),
+            Some(("gcp", "europe-west9"))
+        );
+        assert_eq!(lookup(&ranges, "2001:db8:2::1"), Some(("gcp", "us-east4")));
+    }
+
+    #[test]
+    fn azure_regional_tags_win_over_global_ones() {
+        let ranges = ranges(
+            r#"{"changeNumber": 1, "values": [
+                {"name": "AzureCloud", "properties": {"region": "",
+                    "addressPrefixes": ["198.51.100.0/24", "203.0.113.0/24"]}},
+                {"name": "AzureCloud.westeurope", "properties": {"region": "westeurope",
+                    "addressPrefixes": ["198.51.100.0/24"]}}
+            ]}"#,
+        );
+        assert_eq!(
+            lookup(&ranges, "198.51.100.7"),
+            Some(("azure", "westeurope"))
+        );
+        assert_eq!(lookup(&ranges, "203.0.113.7"), Some(("azure", "global")));
+    }
+}
@@ -1,8 +1,9 @@
 use crate::OutputFormat;
 use crate::Result;
+use ipnet::IpNet;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
 use std::collections::VecDeque;
+use std::collections::{HashMap, HashSet};
 use std::net::IpAddr;
 use std::sync::Arc;
 use tokio::sync::Mutex;
@@ -10,11 +11,46 @@ use trust_dns_proto::error::ProtoErrorKind;
 use trust_dns_proto::rr;
 use trust_dns_resolver::error::{ResolveError, ResolveErrorKind};
 
+// Joins the names in a `Chain` when it has to be represented as a single string.
+const CHAIN_SEPARATOR: &str = " -> ";
+
+// The columns of the csv, every field of every kind of response. Each row leaves the columns its
+// kind doesn't have empty.
+const CSV_COLUMNS: &[&str] = &[
+    "query",
+    "name",
+    "ip",
+    "value",
+    "type",
+    "ttl",
+    "is_wildcard",
+    "wildcard_zone",
+    "provider",
+    "region",
+    "authority",
+    "subnet",
+    "dnssec",
+    "chain",
+    "zone",
+    "signed",
+    "target",
+    "service",
+    "response_code",
+    "incomplete",
+    "reason",
+];
+
+/// The (query, type, value, authority, subnet) a record is stored under in the `ResultsCache`. The
+/// authority is only set when querying the authoritative nameservers directly, and the subnet when
+/// sending the EDNS Client Subnet option, so each of them gets their own answer.
+pub(crate) type Key = (String, String, String, String, String);
+
 /// The ResultsCache is a struct that the resulting records will be written to before being serialized
-/// into a json or csv file. They key is the `IpAddr` for A or AAAA records, and Name if record type is CNAME.
+/// into a json or csv file. They key is the query, the record type and the value of the record, so
+/// hosts that share an answer (like a CDN address) don't overwrite each other.
 #[derive(Debug)]
 pub(crate) struct ResultsCache {
-    pub inner: Mutex<HashMap<String, ResolveResponse>>,
+    pub inner: Mutex<HashMap<Key, ResolveResponse>>,
 }
 
 impl ResultsCache {
@@ -41,23 +77,78 @@ impl ResultsCache {
         map.extend(records.drain(..).map(|r| (r.key(), r)));
     }
 
-    pub async fn records(&self) -> HashMap<String, ResolveResponse> {
+    pub async fn records(&self) -> HashMap<Key, ResolveResponse> {
         let map = self.inner.lock().await;
         map.clone()
     }
 
-    pub(crate) async fn set_wildcard(&self, key: &str) {
+    /// Returns the values of every answer in the cache, grouped by the query they answered.
+    /// Queries that only returned errors aren't included.
+    pub(crate) async fn answers(&self) -> HashMap<String, HashSet<String>> {
+        let lock = self.inner.lock().await;
+        let mut answers: HashMap<String, HashSet<String>> = HashMap::new();
+        for ((query, _, value, _, _), record) in lock.iter() {
+            if let ResolveResponse::IpRecord { .. } | ResolveResponse::Record { .. } = record {
+                answers
+                    .entry(query.clone())
+                    .or_default()
+                    .insert(value.clone());
+            }
+        }
+        answers
+    }
+
+    /// Marks every record of the queries in `zones` as a wildcard answer, from the wildcard at
+    /// the zone the query maps to.
+    pub(crate) async fn set_wildcards(&self, zones: &HashMap<String, String>) {
         let mut lock = self.inner.lock().await;
 
-        if let Some(record) = lock.get_mut(key) {
-            if let ResolveResponse::IpRecord { is_wildcard, .. }
-            | ResolveResponse::Record { is_wildcard, .. } = record
+        for record in lock.values_mut() {
+            if let ResolveResponse::IpRecord {
+                query,
+                is_wildcard,
+                wildcard_zone,
+                ..
+            }
+            | ResolveResponse::Record {
+                query,
+                is_wildcard,
+                wildcard_zone,
+                ..
+            } = record
             {
-                *is_wildcard = true;
+                if let Some(zone) = zones.get(query) {
+                    *is_wildcard = true;
+                    *wildcard_zone = Some(zone.clone());
+                }
             }
         }
     }
 
+    /// Sets the DNSSEC status of every record of the queries in `statuses`.
+    pub(crate) async fn set_dnssec(&self, statuses: &HashMap<String, Dnssec>) {
+        let mut lock = self.inner.lock().await;
+
+        for record in lock.values_mut() {
+            if let ResolveResponse::IpRecord { query, dnssec, .. }
+            | ResolveResponse::Record { query, dnssec, .. } = record
+            {
+                if let Some(status) = statuses.get(query) {
+                    *dnssec = Some(*status);
+                }
+            }
+        }
+    }
+
+    /// Only keeps the records for which `keep` returns true.
+    pub(crate) async fn retain<F>(&self, mut keep: F)
+    where
+        F: FnMut(&Key, &ResolveResponse) -> bool,
+    {
+        let mut lock = self.inner.lock().await;
+        lock.retain(|key, record| keep(key, record));
+    }
+
     pub(crate) async fn results(&self, format: &OutputFormat) -> Result<Vec<u8>> {
         match format {
             OutputFormat::Csv => self.csv().await,
@@ -72,43 +163,128 @@ impl ResultsCache {
         Ok(serde_json::to_vec_pretty(&vals).unwrap())
     }
 
-    /// Serializes the contents of the `ResultsCache` into a csv.
+    /// Serializes the contents of the `ResultsCache` into a csv, with the same columns for every
+    /// kind of response.
     async fn csv(&self) -> Result<Vec<u8>> {
         let mut wtr = csv::Writer::from_writer(vec![]);
+        wtr.write_record(CSV_COLUMNS)?;
         let lock = self.inner.lock().await;
-        lock.values().map(|v| wtr.serialize(v)).for_each(drop);
+        for v in lock.values() {
+            let fields = serde_json::to_value(v)?;
+            wtr.write_record(CSV_COLUMNS.iter().map(|column| csv_field(&fields[column])))?;
+        }
         Ok(wtr.into_inner()?)
     }
 }
 
-// Represents the different kind of reponses we will get when making a DNS query.
+/// Formats a field of a response for the csv. Csv can't hold a list inside a column, so the
+/// names of a chain are joined into one.
+fn csv_field(field: &serde_json::Value) -> String {
+    match field {
+        serde_json::Value::Null => String::new(),
+        serde_json::Value::String(s) => s.clone(),
+        serde_json::Value::Array(names) => names
+            .iter()
+            .map(csv_field)
+            .collect::<Vec<_>>()
+            .join(CHAIN_SEPARATOR),
+        other => other.to_string(),
+    }
+}
+
+/// The outcome of validating the answers of a host with DNSSEC.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Dnssec {
+    /// The answers, or the denial that there are any, validated up to the root.
+    Secure,
+    /// The zone the host is in isn't signed, or one of its CNAMEs leads into a zone that isn't.
+    Insecure,
+    /// The zone is signed, but the answers failed to validate.
+    Bogus,
+    /// Whether the answers validate couldn't be determined.
+    Indeterminate,
+}
+
+// Represents the different kind of reponses we will get when making a DNS query. Since the variants
+// aren't tagged, the order matters when reading them back in, an `IpRecord` has every field of a
+// `Record` so it has to be tried first.
 #[derive(Deserialize, Serialize, Debug, Clone)]
 #[serde(untagged)]
 pub(crate) enum ResolveResponse {
-    Record {
+    IpRecord {
         query: String,
         name: String,
-        #[serde(rename(serialize = "type"))]
+        // Always written, even when it's null, which is what tells it apart from a `Record`.
+        #[serde(rename = "ip", deserialize_with = "required")]
+        value: Option<IpAddr>,
+        #[serde(rename = "type")]
         kind: String,
         ttl: u32,
         is_wildcard: bool,
+        wildcard_zone: Option<String>,
+        provider: Option<String>,
+        region: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        authority: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        subnet: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        dnssec: Option<Dnssec>,
     },
-    IpRecord {
+    Record {
         query: String,
         name: String,
-        #[serde(rename(serialize = "ip"))]
-        value: Option<IpAddr>,
-        #[serde(rename(serialize = "type"))]
+        // The data of the records that aren't a CNAME, e.g `10 mail.example.com.` for an MX.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        value: Option<String>,
+        #[serde(rename = "type")]
         kind: String,
         ttl: u32,
         is_wildcard: bool,
+        wildcard_zone: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        authority: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        subnet: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        dnssec: Option<Dnssec>,
     },
+    /// The ordered list of names a query went through to reach one of its final addresses, e.g
+    /// `a.example.com. -> b.cdn.net. -> 1.2.3.4`.
+    Chain { query: String, chain: Vec<String> },
+    /// Whether a zone that one of the queries is in has been signed.
+    Zone { zone: String, signed: bool },
+    /// A host whose CNAME target doesn't exist, so whoever registers it gets the host. The
+    /// service is set when the target belongs to one that lets anyone claim its names.
+    Takeover {
+        query: String,
+        target: String,
+        service: Option<String>,
+    },
+    /// Written along with the results when the run was interrupted, so they can't be mistaken
+    /// for the results of every host.
+    Incomplete { incomplete: bool, reason: String },
     Error {
         query: String,
         response_code: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        authority: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        subnet: Option<String>,
     },
 }
 
+/// Makes an `Option` field required when deserializing, instead of defaulting to `None` when it's
+/// missing.
+fn required<'de, D, T>(deserializer: D) -> std::result::Result<Option<T>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::<T>::deserialize(deserializer)
+}
+
 impl ResolveResponse {
     /// A wrapper around the `From` trait, but adds the query if the record is a CNAME.
     pub(crate) fn new(record: &rr::resource::Record, q: Arc<String>) -> ResolveResponse {
@@ -123,16 +299,130 @@ impl ResolveResponse {
         }
     }
 
-    /// Returns the fields that we use for keys inside the ResultsCache. This is a clone for now, but
-    /// in the future we could return an `Arc<String>` to avoid the clone.
-    pub(crate) fn key(&self) -> String {
+    /// Returns the (query, type, value, authority) that we use for keys inside the ResultsCache.
+    pub(crate) fn key(&self) -> Key {
+        match self {
+            ResolveResponse::IpRecord {
+                query,
+                kind,
+                value,
+                authority,
+                subnet,
+                ..
+            } => (
+                query.clone(),
+                kind.clone(),
+                value.map(|ip| ip.to_string()).unwrap_or_default(),
+                authority.clone().unwrap_or_default(),
+                subnet.clone().unwrap_or_default(),
+            ),
+            ResolveResponse::Record {
+                query,
+                kind,
+                name,
+                value,
+                authority,
+                subnet,
+                ..
+            } => (
+                query.clone(),
+                kind.clone(),
+                value.clone().unwrap_or_else(|| name.clone()),
+                authority.clone().unwrap_or_default(),
+                subnet.clone().unwrap_or_default(),
+            ),
+            ResolveResponse::Chain { query, chain } => (
+                query.clone(),
+                "CHAIN".to_owned(),
+                chain.join(CHAIN_SEPARATOR),
+                String::new(),
+                String::new(),
+            ),
+            ResolveResponse::Zone { zone, .. } => (
+                zone.clone(),
+                "ZONE".to_owned(),
+                String::new(),
+                String::new(),
+                String::new(),
+            ),
+            ResolveResponse::Takeover { query, target, .. } => (
+                query.clone(),
+                "TAKEOVER".to_owned(),
+                target.clone(),
+                String::new(),
+                String::new(),
+            ),
+            ResolveResponse::Incomplete { reason, .. } => (
+                String::new(),
+                "INCOMPLETE".to_owned(),
+                reason.clone(),
+                String::new(),
+                String::new(),
+            ),
+            ResolveResponse::Error {
+                query,
+                response_code,
+                authority,
+                subnet,
+            } => (
+                query.clone(),
+                "ERROR".to_owned(),
+                response_code.clone(),
+                authority.clone().unwrap_or_default(),
+                subnet.clone().unwrap_or_default(),
+            ),
+        }
+    }
+
+    /// Records which authoritative nameserver the response came from.
+    pub(crate) fn set_authority(&mut self, nameserver: IpAddr) {
+        match self {
+            ResolveResponse::IpRecord { authority, .. }
+            | ResolveResponse::Record { authority, .. }
+            | ResolveResponse::Error { authority, .. } => *authority = Some(nameserver.to_string()),
+            _ => {}
+        }
+    }
+
+    /// Records which EDNS Client Subnet the answer was asked for.
+    pub(crate) fn set_subnet(&mut self, client_subnet: &IpNet) {
         match self {
-            ResolveResponse::IpRecord { value, .. } => value.unwrap().to_string(),
-            ResolveResponse::Record { name, .. } => name.clone(),
-            ResolveResponse::Error { query, .. } => query.clone(),
+            ResolveResponse::IpRecord { subnet, .. }
+            | ResolveResponse::Record { subnet, .. }
+            | ResolveResponse::Error { subnet, .. } => *subnet = Some(client_subnet.to_string()),
+            _ => {}
         }
     }
 
+    /// Follows the CNAME records from the query to the name that holds the addresses, and returns
+    /// a `Chain` for every address that name resolved to. Queries that didn't go through a CNAME
+    /// are already fully described by their `IpRecord`s, so no chains are returned for them.
+    pub(crate) fn chains<'a, I>(records: I, query: &str) -> Vec<ResolveResponse>
+    where
+        I: IntoIterator<Item = &'a rr::resource::Record>,
+    {
+        let records: Vec<&rr::resource::Record> = records.into_iter().collect();
+        let chain = cname_chain(records.iter().copied(), query);
+        if chain.len() == 1 {
+            return Vec::new();
+        }
+
+        let end = chain.last().unwrap();
+        records
+            .iter()
+            .filter(|record| normalize(&record.name().to_utf8()) == *end)
+            .filter_map(|record| record.rdata().to_ip_addr())
+            .map(|ip| {
+                let mut chain = chain.clone();
+                chain.push(ip.to_string());
+                ResolveResponse::Chain {
+                    query: query.to_owned(),
+                    chain,
+                }
+            })
+            .collect()
+    }
+
     /// Extracts the errors we want from the `ResolveError`
     pub(crate) fn from_error(error: ResolveError) -> Option<ResolveResponse> {
         //TODO: How can we get the query that triggered the error, if it doesn't actually contain
@@ -144,10 +434,14 @@ impl ResolveResponse {
             ResolveErrorKind::Message(m) => Some(ResolveResponse::Error {
                 query: String::default(),
                 response_code: m.to_string(),
+                authority: None,
+                subnet: None,
             }),
             ResolveErrorKind::Msg(m) => Some(ResolveResponse::Error {
                 query: String::default(),
                 response_code: m.to_string(),
+                authority: None,
+                subnet: None,
             }),
             ResolveErrorKind::NoRecordsFound {
                 query,
@@ -156,6 +450,8 @@ impl ResolveResponse {
             } => Some(ResolveResponse::Error {
                 query: query.name().to_string(),
                 response_code: response_code.to_string(),
+                authority: None,
+                subnet: None,
             }),
 
             // SERVFAIL are returned as a `ProtoErrorKind::Msg` or `ProtoErrorKind::Message` ?
@@ -163,10 +459,14 @@ impl ResolveResponse {
                 ProtoErrorKind::Msg(s) => Some(ResolveResponse::Error {
                     query: String::default(),
                     response_code: s.to_string(),
+                    authority: None,
+                    subnet: None,
                 }),
                 ProtoErrorKind::Message(s) => Some(ResolveResponse::Error {
                     query: String::default(),
                     response_code: s.to_string(),
+                    authority: None,
+                    subnet: None,
                 }),
                 _ => None,
             },
@@ -176,6 +476,14 @@ impl ResolveResponse {
     }
 }
 
+/// The presentation format of `rdata`, which some types like OPT don't have.
+fn rdata(rdata: &rr::RData) -> Option<String> {
+    use std::fmt::Write;
+    let mut value = String::new();
+    write!(value, "{}", rdata).ok()?;
+    Some(value)
+}
+
 // Handles conversion from a `resource::Record` to a `ResolveResponse`. Since we only care about a
 // few of the record types this is not exhaustive.
 impl From<&rr::resource::Record> for ResolveResponse {
@@ -194,21 +502,325 @@ impl From<&rr::resource::Record> for ResolveResponse {
                 kind: kind.to_string(),
                 ttl,
                 is_wildcard,
+                wildcard_zone: None,
+                provider: None,
+                region: None,
+                authority: None,
+                subnet: None,
+                dnssec: None,
             },
             RecordType::CNAME => Self::Record {
                 query: String::default(),
                 name: record.rdata().as_cname().unwrap().to_utf8(),
+                value: None,
                 kind: kind.to_string(),
                 ttl,
                 is_wildcard,
+                wildcard_zone: None,
+                authority: None,
+                subnet: None,
+                dnssec: None,
             },
             _ => Self::Record {
                 query: String::default(),
                 name,
+                value: rdata(record.rdata()),
                 kind: kind.to_string(),
                 ttl,
                 is_wildcard,
+                wildcard_zone: None,
+                authority: None,
+                subnet: None,
+                dnssec: None,
+            },
+        }
+    }
+}
+
+/// Names are compared in lowercase and fully qualified, since the case and the trailing dot
+/// differ between the queries, the records and the errors.
+pub(crate) fn normalize(name: &str) -> String {
+    format!("{}.", name.trim_end_matches('.').to_lowercase())
+}
+
+/// Follows the CNAME records in `records` from `query`, and returns every name along the way,
+/// starting with `query` itself. The names are normalized.
+pub(crate) fn cname_chain<'a, I>(records: I, query: &str) -> Vec<String>
+where
+    I: IntoIterator<Item = &'a rr::resource::Record>,
+{
+    let aliases: HashMap<String, String> = records
+        .into_iter()
+        .filter_map(|record| {
+            let target = record.rdata().as_cname()?;
+            Some((
+                normalize(&record.name().to_utf8()),
+                normalize(&target.to_utf8()),
+            ))
+        })
+        .collect();
+
+    let mut chain = vec![normalize(query)];
+    while let Some(target) = aliases.get(chain.last().unwrap()) {
+        // A CNAME loop would otherwise keep us here forever.
+        if chain.contains(target) {
+            break;
+        }
+        chain.push(target.clone());
+    }
+    chain
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use std::str::FromStr;
+    use trust_dns_proto::rr::{Name, RData, Record};
+
+    fn cname(name: &str, target: &str) -> Record {
+        Record::from_rdata(
+            Name::from_str(name).unwrap(),
+            300,
+            RData::CNAME(Name::from_str(target).unwrap()),
+        )
+    }
+
+    fn a(name: &str, ip: [u8; 4]) -> Record {
+        Record::from_rdata(
+            Name::from_str(name).unwrap(),
+            300,
+            RData::A(Ipv4Addr::from(ip)),
+        )
+    }
+
+    fn chains(records: &[Record], query: &str) -> Vec<Vec<String>> {
+        let mut chains: Vec<Vec<String>> = ResolveResponse::chains(records, query)
+            .into_iter()
+            .map(|response| match response {
+                ResolveResponse::Chain { chain, .. } => chain,
+                other => panic!("not a chain: {:?}", other),
+            })
+            .collect();
+        chains.sort();
+        chains
+    }
+
+    #[test]
+    fn chains_follow_every_hop() {
+        let records = [
+            cname("www.example.com.", "www.example.cdn.net."),
+            cname("WWW.example.cdn.net.", "edge.cdn.net."),
+            a("edge.cdn.net.", [192, 0, 2, 1]),
+            a("edge.cdn.net.", [192, 0, 2, 2]),
+        ];
+        let hops = ["www.example.com.", "www.example.cdn.net.", "edge.cdn.net."];
+        let chain = |ip: &str| {
+            let mut chain: Vec<String> = hops.iter().map(|h| h.to_string()).collect();
+            chain.push(ip.to_owned());
+            chain
+        };
+        assert_eq!(
+            chains(&records, "www.example.com."),
+            vec![chain("192.0.2.1"), chain("192.0.2.2")]
+        );
+    }
+
+    #[test]
+    fn chains_stop_at_a_loop() {
+        let records = [
+            cname("a.example.com.", "b.example.com."),
+            cname("b.example.com.", "a.example.com."),
+        ];
+        assert!(chains(&records, "a.example.com.").is_empty());
+    }
+
+    #[test]
+    fn cname_chains_are_normalized() {
+        let records = [
+            cname("WWW.example.com.", "edge.CDN.net."),
+            cname("edge.cdn.net.", "www.example.com."),
+        ];
+        assert_eq!(
+            cname_chain(&records, "www.Example.com"),
+            vec!["www.example.com.", "edge.cdn.net."]
+        );
+        assert_eq!(normalize("Example.COM"), normalize("example.com."));
+    }
+
+    #[test]
+    fn no_chains_without_a_cname() {
+        let records = [a("example.com.", [192, 0, 2, 1])];
+        assert!(chains(&records, "example.com.").is_empty());
+    }
+
+    #[test]
+    fn no_chains_when_the_target_has_no_addresses() {
+        let records = [cname("a.example.com.", "gone.example.net.")];
+        assert!(chains(&records, "a.example.com.").is_empty());
+    }
+
+    #[tokio::test]
+    async fn hosts_sharing_an_address_keep_their_own_rows() {
+        let cache = ResultsCache::new();
+        let mut responses: VecDeque<ResolveResponse> = ["a.example.com.", "b.example.com."]
+            .iter()
+            .map(|host| ResolveResponse::new(&a(host, [192, 0, 2, 1]), Arc::new(host.to_string())))
+            .collect();
+        cache.insert(&mut responses).await;
+        assert_eq!(cache.num_results().await, 2);
+
+        let mut wildcards = HashMap::new();
+        wildcards.insert("a.example.com.".to_owned(), "example.com".to_owned());
+        cache.set_wildcards(&wildcards).await;
+
+        let mut rows: Vec<(String, bool, Option<String>)> = cache
+            .records()
+            .await
+            .into_values()
+            .map(|record| match record {
+                ResolveResponse::IpRecord {
+                    query,
+                    value,
+                    is_wildcard,
+                    wildcard_zone,
+                    ..
+                } => {
+                    assert_eq!(value, Some(IpAddr::from([192, 0, 2, 1])));
+                    (query, is_wildcard, wildcard_zone)
+                }
+                other => panic!("not an address: {:?}", other),
+            })
+            .collect();
+        rows.sort();
+        assert_eq!(
+            rows,
+            vec![
+                (
+                    "a.example.com.".to_owned(),
+                    true,
+                    Some("example.com".to_owned())
+                ),
+                ("b.example.com.".to_owned(), false, None),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn csv_holds_every_kind_of_row() {
+        let cache = ResultsCache::new();
+        let query = Arc::new("www.example.com.".to_owned());
+        let records = [
+            cname("www.example.com.", "edge.cdn.net."),
+            a("edge.cdn.net.", [192, 0, 2, 1]),
+        ];
+        let mut responses: VecDeque<ResolveResponse> = records
+            .iter()
+            .map(|record| ResolveResponse::new(record, Arc::clone(&query)))
+            .collect();
+        responses.extend(ResolveResponse::chains(&records, &query));
+        responses.push_back(ResolveResponse::Error {
+            query: "gone.example.com.".to_owned(),
+            response_code: "Non-Existent Domain".to_owned(),
+            authority: None,
+            subnet: None,
+        });
+        cache.insert(&mut responses).await;
+
+        responses.push_back(ResolveResponse::Takeover {
+            query: "old.example.com.".to_owned(),
+            target: "old.herokuapp.com.".to_owned(),
+            service: Some("heroku".to_owned()),
+        });
+        cache.insert(&mut responses).await;
+        cache
+            .set_wildcards(&HashMap::from([(
+                "www.example.com.".to_owned(),
+                "example.com".to_owned(),
+            )]))
+            .await;
+
+        let csv = cache.results(&OutputFormat::Csv).await.unwrap();
+        let mut reader = csv::Reader::from_reader(csv.as_slice());
+        assert_eq!(reader.headers().unwrap(), CSV_COLUMNS);
+        let rows: Vec<HashMap<String, String>> =
+            reader.deserialize().map(|row| row.unwrap()).collect();
+        let row = |column: &str, value: &str| {
+            rows.iter()
+                .find(|row| row[column] == value)
+                .unwrap_or_else(|| panic!("no row with {} {}", column, value))
+        };
+
+        let ip = row("ip", "192.0.2.1");
+        assert_eq!(ip["name"], "edge.cdn.net.");
+        assert_eq!(ip["type"], "A");
+        assert_eq!(ip["is_wildcard"], "true");
+        assert_eq!(ip["wildcard_zone"], "example.com");
+        assert_eq!(ip["target"], "");
+        assert_eq!(
+            row("type", "CNAME")["name"],
+            "edge.cdn.net.",
+            "the CNAME's target is its name"
+        );
+        let chain = row("chain", "www.example.com. -> edge.cdn.net. -> 192.0.2.1");
+        assert_eq!(chain["query"], "www.example.com.");
+        assert_eq!(chain["ip"], "");
+        let error = row("query", "gone.example.com.");
+        assert_eq!(error["response_code"], "Non-Existent Domain");
+        assert_eq!(error["type"], "");
+        let takeover = row("query", "old.example.com.");
+        assert_eq!(takeover["target"], "old.herokuapp.com.");
+        assert_eq!(takeover["service"], "heroku");
+    }
+
+    #[test]
+    fn every_field_has_a_csv_column() {
+        let responses = [
+            ResolveResponse::new(&a("a.example.com.", [192, 0, 2, 1]), Arc::new("a".into())),
+            ResolveResponse::new(
+                &cname("a.example.com.", "b.example.com."),
+                Arc::new("a".into()),
+            ),
+            ResolveResponse::Chain {
+                query: "a".into(),
+                chain: vec![],
+            },
+            ResolveResponse::Zone {
+                zone: "example.com".into(),
+                signed: true,
             },
+            ResolveResponse::Takeover {
+                query: "a".into(),
+                target: "b".into(),
+                service: None,
+            },
+            ResolveResponse::Incomplete {
+                incomplete: true,
+                reason: "interrupted".into(),
+            },
+            ResolveResponse::Error {
+                query: "a".into(),
+                response_code: "Refused".into(),
+                authority: Some("192.0.2.53".into()),
+                subnet: None,
+            },
+        ];
+        for mut response in responses {
+            response.set_authority(IpAddr::from([192, 0, 2, 53]));
+            response.set_subnet(&"192.0.2.0/24".parse().unwrap());
+            if let ResolveResponse::IpRecord { dnssec, .. }
+            | ResolveResponse::Record { dnssec, .. } = &mut response
+            {
+                *dnssec = Some(Dnssec::Secure);
+            }
+            let fields = serde_json::to_value(&response).unwrap();
+            for field in fields.as_object().unwrap().keys() {
+                assert!(
+                    CSV_COLUMNS.contains(&field.as_str()),
+                    "no column for {}",
+                    field
+                );
+            }
         }
     }
 }
@@ -0,0 +1,270 @@
+use crate::authority::Authorities;
+use crate::clients::Client;
+use crate::data::{Dnssec, ResolveResponse, ResultsCache};
+use futures::StreamExt;
+use std::collections::{HashMap, HashSet, VecDeque};
+use tracing::info;
+use trust_dns_proto::error::ProtoErrorKind;
+use trust_dns_proto::op::ResponseCode;
+use trust_dns_proto::rr::dnssec::rdata::DNSSECRecordType;
+use trust_dns_proto::rr::record_type::RecordType;
+use trust_dns_proto::xfer::dns_request::DnsRequestOptions;
+use trust_dns_resolver::config::{ResolverConfig, ResolverOpts};
+use trust_dns_resolver::error::{ResolveError, ResolveErrorKind};
+
+/// Validates the answers of every host in the cache with DNSSEC. Each host's zone is checked for
+/// DNSKEY records first, the hosts in unsigned zones are `insecure`, and the hosts in signed
+/// zones are looked up again with validation to tell `secure` answers from `bogus` ones. A row
+/// for every zone is added to the results, reporting whether it's signed.
+pub(crate) async fn validate(
+    config: &ResolverConfig,
+    options: ResolverOpts,
+    cache: &ResultsCache,
+    concurrency: usize,
+) {
+    let answers = cache.answers().await;
+    let resolver = Client::new(config.clone(), options);
+    let validating = Client::new(
+        config.clone(),
+        ResolverOpts {
+            validate: true,
+            ..options
+        },
+    );
+    let authorities = Authorities::new(resolver.clone());
+
+    let zones: HashMap<&String, Option<String>> = futures::stream::iter(answers.keys())
+        .map(|query| {
+            let authorities = &authorities;
+            async move { (query, authorities.zone(query).await.map(|(zone, _)| zone)) }
+        })
+        .buffer_unordered(concurrency)
+        .collect()
+        .await;
+
+    let unique: HashSet<&String> = zones.values().flatten().collect();
+    let signed: HashMap<&String, Option<bool>> = futures::stream::iter(unique)
+        .map(|zone| {
+            let resolver = &resolver;
+            async move { (zone, is_signed(resolver, zone).await) }
+        })
+        .buffer_unordered(concurrency)
+        .collect()
+        .await;
+
+    let statuses: HashMap<String, Dnssec> = futures::stream::iter(zones.iter())
+        .map(|(query, zone)| {
+            let (validating, resolver, authorities) = (&validating, &resolver, &authorities);
+            let signed = zone
+                .as_ref()
+                .and_then(|zone| signed.get(zone).copied().flatten());
+            async move {
+                let status = match signed {
+                    Some(true) => validated(validating, resolver, authorities, query).await,
+                    Some(false) => Dnssec::Insecure,
+                    None => Dnssec::Indeterminate,
+                };
+                info!("{} is {:?}", query, status);
+                (query.to_string(), status)
+            }
+        })
+        .buffer_unordered(concurrency)
+        .collect()
+        .await;
+
+    cache.set_dnssec(&statuses).await;
+    let mut reports: VecDeque<ResolveResponse> = signed
+        .into_iter()
+        .filter_map(|(zone, signed)| {
+            signed.map(|signed| ResolveResponse::Zone {
+                zone: format!("{}.", zone),
+                signed,
+            })
+        })
+        .collect();
+    cache.insert(&mut reports).await;
+}
+
+/// Returns whether `zone` publishes DNSKEY records, or `None` if we couldn't find out.
+async fn is_signed(resolver: &Client, zone: &str) -> Option<bool> {
+    let kind = RecordType::DNSSEC(DNSSECRecordType::DNSKEY);
+    match resolver
+        .lookup(format!("{}.", zone), kind, DnsRequestOptions::default())
+        .await
+    {
+        Ok(lookup) => Some(lookup.record_iter().any(|r| r.record_type() == kind)),
+        Err(e) => match e.kind() {
+            // The library reports a failed lookup, like a SERVFAIL, as having no records too.
+            ResolveErrorKind::NoRecordsFound {
+                response_code: ResponseCode::NoError | ResponseCode::NXDomain,
+                ..
+            } => Some(false),
+            _ => None,
+        },
+    }
+}
+
+/// Looks up `query` with validation, its zone being signed. Only answers that were shown not to
+/// validate are `bogus`, anything that couldn't be settled either way is `indeterminate`.
+async fn validated(
+    validating: &Client,
+    resolver: &Client,
+    authorities: &Authorities,
+    query: &str,
+) -> Dnssec {
+    let error = match validating.lookup_ip(query.to_owned()).await {
+        Ok(_) => return Dnssec::Secure,
+        Err(e) => e,
+    };
+    match failure(&error) {
+        Failure::Status(status) => status,
+        Failure::Unsigned(name) => match authorities.zone(&name).await {
+            Some((zone, _)) => match is_signed(resolver, &zone).await {
+                Some(true) => Dnssec::Bogus,
+                Some(false) => Dnssec::Insecure,
+                None => Dnssec::Indeterminate,
+            },
+            None => Dnssec::Indeterminate,
+        },
+    }
+}
+
+/// What the error of a validating lookup says about the answers.
+#[derive(Debug, PartialEq)]
+enum Failure {
+    Status(Dnssec),
+    /// The records at the name came back without signatures, which is only bogus when its zone
+    /// is signed.
+    Unsigned(String),
+}
+
+fn failure(error: &ResolveError) -> Failure {
+    match error.kind() {
+        // The validating resolver only lets a denial through once its NSEC records validated.
+        ResolveErrorKind::NoRecordsFound { .. } => Failure::Status(Dnssec::Secure),
+        ResolveErrorKind::Proto(e) => match e.kind() {
+            // Records without signatures are expected in an unsigned zone, which is where a CNAME
+            // to a CDN usually leads, but not in a signed one.
+            ProtoErrorKind::RrsigsNotPresent { name, .. } => Failure::Unsigned(name.to_utf8()),
+            ProtoErrorKind::Message(message) if *message == "validation failed" => {
+                Failure::Status(Dnssec::Bogus)
+            }
+            _ => Failure::Status(Dnssec::Indeterminate),
+        },
+        _ => Failure::Status(Dnssec::Indeterminate),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stub;
+    use crate::OutputFormat;
+    use serde_json::Value;
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use trust_dns_proto::error::ProtoError;
+    use trust_dns_proto::op::Query;
+    use trust_dns_proto::rr::dnssec::rdata::{DNSSECRData, DNSKEY};
+    use trust_dns_proto::rr::dnssec::Algorithm;
+    use trust_dns_proto::rr::{Name, RData, Record};
+
+    /// Serves `signed.test` with a DNSKEY, `plain.test` without one, and fails the DNSKEY lookups
+    /// of `broken.test`.
+    fn nameserver() -> SocketAddr {
+        stub::nameserver("test.", |query: &Name, kind| {
+            let host = query.to_utf8();
+            let dnskey = RecordType::DNSSEC(DNSSECRecordType::DNSKEY);
+            match (host.as_str(), kind) {
+                ("signed.test." | "plain.test.", RecordType::NS) => {
+                    let ns = RData::NS(stub::name(&format!("ns.{}", host)));
+                    (
+                        ResponseCode::NoError,
+                        vec![Record::from_rdata(query.clone(), 300, ns)],
+                    )
+                }
+                ("ns.signed.test." | "ns.plain.test.", RecordType::A) => {
+                    (ResponseCode::NoError, vec![stub::a(query, [192, 0, 2, 53])])
+                }
+                ("www.plain.test.", RecordType::A) => {
+                    (ResponseCode::NoError, vec![stub::a(query, [192, 0, 2, 1])])
+                }
+                ("signed.test.", kind) if kind == dnskey => {
+                    let key = DNSKEY::new(true, true, false, Algorithm::RSASHA256, vec![1; 64]);
+                    let rdata = RData::DNSSEC(DNSSECRData::DNSKEY(key));
+                    (
+                        ResponseCode::NoError,
+                        vec![Record::from_rdata(query.clone(), 300, rdata)],
+                    )
+                }
+                ("broken.test.", kind) if kind == dnskey => (ResponseCode::ServFail, vec![]),
+                ("signed.test." | "plain.test." | "www.plain.test.", _) => {
+                    (ResponseCode::NoError, vec![])
+                }
+                _ => (ResponseCode::NXDomain, vec![]),
+            }
+        })
+    }
+
+    #[tokio::test]
+    async fn zones_with_a_dnskey_are_signed() {
+        let resolver = Client::new(stub::config(nameserver()), ResolverOpts::default());
+        assert_eq!(is_signed(&resolver, "signed.test").await, Some(true));
+        assert_eq!(is_signed(&resolver, "plain.test").await, Some(false));
+        assert_eq!(is_signed(&resolver, "broken.test").await, None);
+    }
+
+    #[tokio::test]
+    async fn hosts_in_unsigned_zones_are_insecure() {
+        let cache = ResultsCache::new();
+        let host = Arc::new("www.plain.test.".to_owned());
+        let record = stub::a(&stub::name(&host), [192, 0, 2, 1]);
+        cache
+            .insert(&mut VecDeque::from([ResolveResponse::new(&record, host)]))
+            .await;
+        validate(
+            &stub::config(nameserver()),
+            ResolverOpts::default(),
+            &cache,
+            4,
+        )
+        .await;
+
+        let results = cache.results(&OutputFormat::Json).await.unwrap();
+        let rows: Vec<Value> = serde_json::from_slice(&results).unwrap();
+        assert!(rows
+            .iter()
+            .any(|row| row["query"] == "www.plain.test." && row["dnssec"] == "insecure"));
+        assert!(rows
+            .iter()
+            .any(|row| row["zone"] == "plain.test." && row["signed"] == false));
+    }
+
+    #[test]
+    fn failures_are_classified_by_their_error() {
+        let query = Query::query(stub::name("www.signed.test."), RecordType::A);
+        let denied = ResolveError::from(ResolveErrorKind::NoRecordsFound {
+            query: query.clone(),
+            soa: None,
+            negative_ttl: None,
+            response_code: ResponseCode::NXDomain,
+            trusted: true,
+        });
+        assert_eq!(failure(&denied), Failure::Status(Dnssec::Secure));
+
+        let unsigned = ResolveError::from(ProtoError::from(ProtoErrorKind::RrsigsNotPresent {
+            name: stub::name("cdn.example.test."),
+            record_type: RecordType::A,
+        }));
+        assert_eq!(
+            failure(&unsigned),
+            Failure::Unsigned("cdn.example.test.".to_owned())
+        );
+
+        let invalid = ResolveError::from(ProtoError::from("validation failed"));
+        assert_eq!(failure(&invalid), Failure::Status(Dnssec::Bogus));
+
+        let timeout = ResolveError::from(ResolveErrorKind::Timeout);
+        assert_eq!(failure(&timeout), Failure::Status(Dnssec::Indeterminate));
+    }
+}
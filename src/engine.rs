@@ -0,0 +1,442 @@
+use crate::data::ResolveResponse;
+use crate::wire;
+use crate::Result;
+use ipnet::IpNet;
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket as StdUdpSocket};
+use std::ops::RangeInclusive;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::udp::{RecvHalf, SendHalf};
+use tokio::net::UdpSocket;
+use tokio::sync::{oneshot, Mutex, Semaphore};
+use tracing::warn;
+use trust_dns_proto::op::{Message, Query};
+
+/// How often the timeout wheel moves on to its next slot.
+const TICK: Duration = Duration::from_millis(50);
+
+/// The most queries in flight on each socket. Well below the 65536 ids, so a random id is almost
+/// always free on the first try.
+const MAX_IN_FLIGHT: usize = 4096;
+
+/// A query that was sent and is waiting for its response, stored under the socket it was sent
+/// from and its id.
+type Key = (usize, u16);
+
+struct Pending {
+    server: SocketAddr,
+    // The question, which the response has to repeat, so a spoofed response has to guess it too.
+    question: Query,
+    sender: oneshot::Sender<Message>,
+    // The slot of the wheel it expires from, so a stale key left in the wheel by an earlier
+    // query with the same id doesn't expire this one.
+    slot: usize,
+}
+
+/// The queries in flight, and the timeout wheel they expire from. Each slot of the wheel holds
+/// the queries that time out on the same tick, so expiring them never has to look at the rest.
+struct State {
+    pending: HashMap<Key, Pending>,
+    wheel: Vec<Vec<Key>>,
+    cursor: usize,
+}
+
+/// How many sockets the raw engine binds for each address family, unless told otherwise.
+const DEFAULT_SOCKETS: usize = 8;
+
+/// Where the sockets of the raw engine are bound and how many of them there are, for each address
+/// family. Without a port range the ports are picked by the os. Each option is only set when it
+/// was given, so they can be rejected without the raw engine.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct SocketOptions {
+    pub(crate) bind: Option<IpAddr>,
+    pub(crate) ports: Option<RangeInclusive<u16>>,
+    pub(crate) sockets: Option<usize>,
+}
+
+impl SocketOptions {
+    /// Whether any of the options were given.
+    pub(crate) fn is_set(&self) -> bool {
+        self.bind.is_some() || self.ports.is_some() || self.sockets.is_some()
+    }
+
+    /// Binds up to `sockets` sockets on `local`. With a port range, the ports that are already in
+    /// use are skipped, and it's only an error if none of them were free.
+    fn bind_all(&self, local: IpAddr) -> io::Result<Vec<StdUdpSocket>> {
+        let sockets = self.sockets.unwrap_or(DEFAULT_SOCKETS).max(1);
+        let ports = match &self.ports {
+            Some(ports) => ports,
+            None => {
+                return (0..sockets)
+                    .map(|_| StdUdpSocket::bind((local, 0)))
+                    .collect()
+            }
+        };
+
+        // Start somewhere random in the range, so runs sharing it don't fight over the same ports.
+        let len = u32::from(*ports.end()) - u32::from(*ports.start()) + 1;
+        let offset = rand::random::<u32>() % len;
+        let bound: Vec<StdUdpSocket> = (0..len)
+            .map(|i| *ports.start() + ((offset + i) % len) as u16)
+            .filter_map(|port| StdUdpSocket::bind((local, port)).ok())
+            .take(sockets)
+            .collect();
+
+        if bound.is_empty() {
+            let message = format!(
+                "no free ports between {} and {}",
+                ports.start(),
+                ports.end()
+            );
+            return Err(io::Error::new(io::ErrorKind::AddrInUse, message));
+        }
+        Ok(bound)
+    }
+}
+
+/// A lightweight query engine that skips the resolver library, for when the throughput matters
+/// more than anything it does for us. Queries are spread over a small pool of udp sockets for
+/// each address family, and the responses are matched to them by their id. There is no caching
+/// and no following of CNAMEs, the resolvers we send to do all of that already.
+pub(crate) struct RawEngine {
+    // The ipv4 sockets come first, followed by the ipv6 ones.
+    sockets: Vec<Mutex<SendHalf>>,
+    // The queries each socket has room for.
+    in_flight: Vec<Semaphore>,
+    v4: usize,
+    next: AtomicUsize,
+    state: Arc<Mutex<State>>,
+    attempts: usize,
+    retries: AtomicUsize,
+    timeouts: AtomicUsize,
+}
+
+impl std::fmt::Debug for RawEngine {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RawEngine")
+            .field("sockets", &self.sockets.len())
+            .field("v4", &self.v4)
+            .field("attempts", &self.attempts)
+            .finish()
+    }
+}
+
+impl RawEngine {
+    /// Binds the udp sockets described by `options` and starts the tasks that receive their
+    /// responses and expire the queries that weren't answered within `wait`. Each query is sent up
+    /// to `attempts` times.
+    pub(crate) fn new(options: &SocketOptions, wait: Duration, attempts: usize) -> Result<Self> {
+        let slots = (wait.as_millis() / TICK.as_millis()) as usize + 2;
+        let state = Arc::new(Mutex::new(State {
+            pending: HashMap::new(),
+            wheel: vec![Vec::new(); slots],
+            cursor: 0,
+        }));
+
+        // Without a bind address both families are used, but hosts without ipv6 only get the
+        // ipv4 sockets.
+        let mut bound = Vec::new();
+        let v4;
+        match options.bind {
+            Some(IpAddr::V4(_)) | None => {
+                let local = options.bind.unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+                bound.extend(options.bind_all(local)?);
+                v4 = bound.len();
+                if options.bind.is_none() {
+                    match options.bind_all(IpAddr::V6(Ipv6Addr::UNSPECIFIED)) {
+                        Ok(sockets) => bound.extend(sockets),
+                        Err(e) => warn!("not using ipv6, binding failed: {}", e),
+                    }
+                }
+            }
+            Some(local) => {
+                v4 = 0;
+                bound.extend(options.bind_all(local)?);
+            }
+        }
+
+        let mut senders = Vec::with_capacity(bound.len());
+        for (index, socket) in bound.into_iter().enumerate() {
+            socket.set_nonblocking(true)?;
+            let (receiver, sender) = UdpSocket::from_std(socket)?.split();
+            tokio::spawn(receive(index, receiver, Arc::clone(&state)));
+            senders.push(Mutex::new(sender));
+        }
+        tokio::spawn(expire(Arc::clone(&state)));
+
+        Ok(Self {
+            in_flight: senders
+                .iter()
+                .map(|_| Semaphore::new(MAX_IN_FLIGHT))
+                .collect(),
+            sockets: senders,
+            v4,
+            next: AtomicUsize::new(0),
+            state,
+            attempts: attempts.max(1),
+            retries: AtomicUsize::new(0),
+            timeouts: AtomicUsize::new(0),
+        })
+    }
+
+    /// Sends `request` to `server` and waits for its response, resending it on another socket if
+    /// it times out. Every time it's sent is counted in `sent`.
+    pub(crate) async fn query(
+        &self,
+        server: SocketAddr,
+        mut request: Message,
+        sent: &AtomicUsize,
+    ) -> Result<Message> {
+        let (first, count) = match server {
+            SocketAddr::V4(_) => (0, self.v4),
+            SocketAddr::V6(_) => (self.v4, self.sockets.len() - self.v4),
+        };
+        if count == 0 {
+            let message = format!("no sockets can reach {}", server);
+            return Err(io::Error::new(io::ErrorKind::AddrNotAvailable, message).into());
+        }
+        let question = match request.queries().first() {
+            Some(question) => question.clone(),
+            None => return Err(io::Error::new(io::ErrorKind::InvalidInput, "no question").into()),
+        };
+
+        for attempt in 0..self.attempts {
+            if attempt > 0 {
+                self.retries.fetch_add(1, Ordering::Relaxed);
+            }
+            let index = first + self.next.fetch_add(1, Ordering::Relaxed) % count;
+            // Held until the query is answered or expires.
+            let _permit = self.in_flight[index].acquire().await;
+            let (sender, receiver) = oneshot::channel();
+
+            // The socket is never more than a sixteenth full, so this rarely takes another id.
+            let key = loop {
+                let key = (index, rand::random());
+                let mut state = self.state.lock().await;
+                if state.pending.contains_key(&key) {
+                    continue;
+                }
+                // The slot before the cursor is the last one the wheel gets to.
+                let slot = (state.cursor + state.wheel.len() - 1) % state.wheel.len();
+                state.wheel[slot].push(key);
+                state.pending.insert(
+                    key,
+                    Pending {
+                        server,
+                        question: question.clone(),
+                        sender,
+                        slot,
+                    },
+                );
+                break key;
+            };
+
+            request.set_id(key.1);
+            sent.fetch_add(1, Ordering::Relaxed);
+            let result = self.sockets[index]
+                .lock()
+                .await
+                .send_to(&request.to_vec()?, &server)
+                .await;
+            if let Err(e) = result {
+                self.state.lock().await.pending.remove(&key);
+                return Err(e.into());
+            }
+
+            // The sender is dropped when the query expires, so an error means it timed out.
+            if let Ok(response) = receiver.await {
+                return Ok(response);
+            }
+        }
+        self.timeouts.fetch_add(1, Ordering::Relaxed);
+        Err(io::Error::new(io::ErrorKind::TimedOut, "query timed out").into())
+    }
+
+    /// The number of queries that were sent again after timing out.
+    pub(crate) fn retries(&self) -> usize {
+        self.retries.load(Ordering::Relaxed)
+    }
+
+    /// The number of queries that timed out on every attempt.
+    pub(crate) fn timeouts(&self) -> usize {
+        self.timeouts.load(Ordering::Relaxed)
+    }
+
+    /// Asks `server` for the A, AAAA and CNAME records of `host` at once, like `wire::lookup`
+    /// does one after the other. Returns the responses along with the number of queries sent,
+    /// retries included.
+    pub(crate) async fn lookup(
+        &self,
+        server: SocketAddr,
+        host: &str,
+        recursion_desired: bool,
+        subnet: Option<&IpNet>,
+    ) -> (VecDeque<ResolveResponse>, usize) {
+        let query = Arc::new(format!("{}.", host.trim_end_matches('.')));
+        let requests = match wire::requests(&query, recursion_desired, subnet) {
+            Ok(requests) => requests,
+            Err(_) => return (VecDeque::new(), 0),
+        };
+        let sent = AtomicUsize::new(0);
+        let lookups = requests
+            .into_iter()
+            .map(|request| self.query(server, request, &sent));
+        let responses = futures::future::join_all(lookups).await;
+        (
+            wire::responses(&query, responses, subnet),
+            sent.into_inner(),
+        )
+    }
+}
+
+/// Hands every response that arrives on the socket to the query it answers. Responses from any
+/// address other than the one the query was sent to, or to another question, are ignored.
+async fn receive(index: usize, mut socket: RecvHalf, state: Arc<Mutex<State>>) {
+    let mut buf = [0u8; wire::MAX_UDP_SIZE];
+    loop {
+        let (len, from) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(e) => {
+                warn!("error receiving on socket {}: {}", index, e);
+                continue;
+            }
+        };
+        let response = match Message::from_vec(&buf[..len]) {
+            Ok(response) => response,
+            Err(_) => continue,
+        };
+
+        let key = (index, response.id());
+        let mut state = state.lock().await;
+        let answers = |pending: &Pending| {
+            pending.server == from
+                && response.queries().first().is_some_and(|question| {
+                    question.name() == pending.question.name()
+                        && question.query_type() == pending.question.query_type()
+                })
+        };
+        if state.pending.get(&key).is_some_and(answers) {
+            if let Some(pending) = state.pending.remove(&key) {
+                // The query may have given up already, in which case nobody is listening.
+                let _ = pending.sender.send(response);
+            }
+        }
+    }
+}
+
+/// Turns the timeout wheel one slot every tick, and expires the queries in it that are still
+/// waiting.
+async fn expire(state: Arc<Mutex<State>>) {
+    let mut interval = tokio::time::interval(TICK);
+    loop {
+        interval.tick().await;
+        let mut state = state.lock().await;
+        let cursor = state.cursor;
+        let expired = std::mem::take(&mut state.wheel[cursor]);
+        for key in expired {
+            if state.pending.get(&key).is_some_and(|p| p.slot == cursor) {
+                state.pending.remove(&key);
+            }
+        }
+        state.cursor = (cursor + 1) % state.wheel.len();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stub;
+    use crate::ReeError;
+    use std::time::Instant;
+    use trust_dns_proto::op::MessageType;
+    use trust_dns_proto::rr::RecordType;
+
+    /// Starts a nameserver on a free port that sends back whatever `respond` makes of each query.
+    fn responder<F>(respond: F) -> SocketAddr
+    where
+        F: Fn(&Message) -> Vec<Message> + Send + 'static,
+    {
+        let socket = StdUdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let addr = socket.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let mut buf = [0; 4096];
+            while let Ok((len, from)) = socket.recv_from(&mut buf) {
+                let request = Message::from_vec(&buf[..len]).unwrap();
+                for response in respond(&request) {
+                    let _ = socket.send_to(&response.to_vec().unwrap(), from);
+                }
+            }
+        });
+        addr
+    }
+
+    /// Answers `request` with the address `ip`.
+    fn answer(request: &Message, ip: [u8; 4]) -> Message {
+        let mut response = request.clone();
+        let owner = request.queries()[0].name().clone();
+        response
+            .set_message_type(MessageType::Response)
+            .add_answer(stub::a(&owner, ip));
+        response
+    }
+
+    fn engine(wait: Duration, attempts: usize) -> RawEngine {
+        let options = SocketOptions {
+            bind: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+            sockets: Some(1),
+            ..SocketOptions::default()
+        };
+        RawEngine::new(&options, wait, attempts).unwrap()
+    }
+
+    fn request() -> Message {
+        wire::query_message("www.example.test.", RecordType::A, true).unwrap()
+    }
+
+    #[tokio::test]
+    async fn responses_must_match_the_id_and_the_question() {
+        let server = responder(|request| {
+            let mut other_id = answer(request, [192, 0, 2, 1]);
+            other_id.set_id(request.id().wrapping_add(1));
+            let mut other_question = answer(request, [192, 0, 2, 2]);
+            other_question.take_queries();
+            other_question.add_query(Query::query(
+                stub::name("other.example.test."),
+                RecordType::A,
+            ));
+            vec![other_id, other_question, answer(request, [192, 0, 2, 3])]
+        });
+        let engine = engine(Duration::from_secs(2), 1);
+        let sent = AtomicUsize::new(0);
+
+        let response = engine.query(server, request(), &sent).await.unwrap();
+        assert_eq!(
+            response.answers()[0].rdata().to_ip_addr(),
+            Some(IpAddr::from([192, 0, 2, 3]))
+        );
+        assert_eq!(sent.into_inner(), 1);
+        assert!(engine.state.lock().await.pending.is_empty());
+    }
+
+    #[tokio::test]
+    async fn unanswered_queries_expire_after_every_attempt() {
+        let server = responder(|_| Vec::new());
+        let wait = Duration::from_millis(200);
+        let engine = engine(wait, 2);
+        let sent = AtomicUsize::new(0);
+
+        let started = Instant::now();
+        let result = engine.query(server, request(), &sent).await;
+        match result {
+            Err(ReeError::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::TimedOut),
+            other => panic!("expected a timeout, got {:?}", other),
+        }
+        assert!(started.elapsed() >= wait * 2);
+        assert_eq!(sent.into_inner(), 2);
+        assert_eq!((engine.retries(), engine.timeouts()), (1, 1));
+        assert!(engine.state.lock().await.pending.is_empty());
+    }
+}
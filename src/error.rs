@@ -11,6 +11,11 @@ pub enum ReeError {
     SendErr(String),
     JoinErr(tokio::task::JoinError),
     ParseInt(std::num::ParseIntError),
+    Proto(trust_dns_proto::error::ProtoError),
+    Timeout(tokio::time::Elapsed),
+    Transfer(String),
+    Walk(String),
+    Engine(String),
 }
 
 impl Error for ReeError {}
@@ -24,6 +29,11 @@ impl fmt::Display for ReeError {
             ReeError::JoinErr(ref err) => err.fmt(f),
             ReeError::CsvErr(ref err) => err.fmt(f),
             ReeError::ParseInt(ref err) => err.fmt(f),
+            ReeError::Proto(ref err) => err.fmt(f),
+            ReeError::Timeout(ref err) => err.fmt(f),
+            ReeError::Transfer(ref err) => err.fmt(f),
+            ReeError::Walk(ref err) => err.fmt(f),
+            ReeError::Engine(ref err) => err.fmt(f),
         }
     }
 }
@@ -58,8 +68,26 @@ impl<T> From<csv::IntoInnerError<T>> for ReeError {
     }
 }
 
+impl From<csv::Error> for ReeError {
+    fn from(err: csv::Error) -> Self {
+        ReeError::CsvErr(err.to_string())
+    }
+}
+
 impl From<std::num::ParseIntError> for ReeError {
     fn from(err: std::num::ParseIntError) -> Self {
         ReeError::ParseInt(err)
     }
 }
+
+impl From<trust_dns_proto::error::ProtoError> for ReeError {
+    fn from(err: trust_dns_proto::error::ProtoError) -> Self {
+        ReeError::Proto(err)
+    }
+}
+
+impl From<tokio::time::Elapsed> for ReeError {
+    fn from(err: tokio::time::Elapsed) -> Self {
+        ReeError::Timeout(err)
+    }
+}
@@ -1,6 +1,6 @@
 use crate::Result;
 use std::fs::File;
-use std::io::{self, BufRead, BufReader};
+use std::io::{self, BufRead, BufReader, Split};
 
 #[derive(Debug)]
 pub struct Input {
@@ -31,3 +31,88 @@ impl Input {
         self.hosts
     }
 }
+
+/// Lazily generates brute-force candidates by prepending every word in a wordlist to a domain, so
+/// the full `word.domain` list never has to exist in memory or on disk. Lines that aren't valid
+/// UTF-8 are skipped, wordlists gathered from the wild often have a few. The lines are counted up
+/// front, so the progress line has a total to show the ETA for.
+#[derive(Debug)]
+pub struct Wordlist {
+    domain: String,
+    words: Split<BufReader<File>>,
+    line: usize,
+    lines: usize,
+}
+
+impl Wordlist {
+    pub fn new(path: &str, domain: &str) -> Self {
+        let open = || File::open(path).expect("unable to read wordlist");
+        Self {
+            domain: domain.trim_end_matches('.').to_owned(),
+            words: BufReader::new(open()).split(b'\n'),
+            line: 0,
+            lines: BufReader::new(open()).split(b'\n').count(),
+        }
+    }
+
+    pub fn domain(&self) -> &str {
+        &self.domain
+    }
+}
+
+impl Iterator for Wordlist {
+    type Item = String;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            self.line += 1;
+            let line = match self.words.next()? {
+                Ok(line) => line,
+                // Reading the rest of the file would keep failing the same way.
+                Err(e) => {
+                    eprintln!("error reading line {} of the wordlist: {}", self.line, e);
+                    return None;
+                }
+            };
+            let line = match String::from_utf8(line) {
+                Ok(line) => line,
+                Err(_) => {
+                    eprintln!(
+                        "skipping line {} of the wordlist, it isn't UTF-8",
+                        self.line
+                    );
+                    continue;
+                }
+            };
+            let word = line.trim();
+            if !word.is_empty() {
+                return Some(format!("{}.{}", word, self.domain));
+            }
+        }
+    }
+
+    /// Blank and skipped lines make it an upper bound.
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, Some(self.lines.saturating_sub(self.line)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_lines_left_bound_the_candidates() {
+        let path = std::env::temp_dir().join(format!("ree-wordlist-{}", std::process::id()));
+        std::fs::write(&path, "www\n\nmail\napi").unwrap();
+        let mut words = Wordlist::new(path.to_str().unwrap(), "example.com.");
+        assert_eq!(words.size_hint(), (0, Some(4)));
+        assert_eq!(words.next().as_deref(), Some("www.example.com"));
+        assert_eq!(words.size_hint(), (0, Some(3)));
+        assert_eq!(words.next().as_deref(), Some("mail.example.com"));
+        assert_eq!(words.next().as_deref(), Some("api.example.com"));
+        assert_eq!(words.next(), None);
+        assert_eq!(words.size_hint(), (0, Some(0)));
+        std::fs::remove_file(path).unwrap();
+    }
+}
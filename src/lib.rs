@@ -1,13 +1,35 @@
 extern crate trust_dns_resolver;
 
+mod authority;
+mod axfr;
+mod cache;
+mod clients;
+mod cloud;
 mod data;
+mod dnssec;
+mod engine;
 mod error;
 mod input;
+mod metrics;
+mod permute;
+mod progress;
 mod resolver;
+mod shutdown;
+mod state;
+mod stats;
+#[cfg(test)]
+mod stub;
+mod suffix;
+mod takeover;
+mod walk;
+mod wire;
 
+pub use crate::cloud::CloudRanges;
 pub use crate::error::ReeError;
-pub use crate::input::Input;
+pub use crate::input::{Input, Wordlist};
+pub use crate::permute::Permutator;
 pub use crate::resolver::Resolver;
+pub use crate::takeover::Fingerprints;
 pub type Result<T> = std::result::Result<T, ReeError>;
 
 #[derive(Debug)]
@@ -15,3 +37,17 @@ pub enum OutputFormat {
     Csv,
     Json,
 }
+
+#[derive(Debug)]
+pub enum WildcardMode {
+    Keep,
+    Flag,
+    Drop,
+    Only,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Engine {
+    Resolver,
+    Raw,
+}
@@ -0,0 +1,161 @@
+use crate::resolver::Resolver;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::prelude::*;
+use tracing::warn;
+
+/// The most of a request we read, all we need is the request line.
+const MAX_REQUEST: usize = 8192;
+
+/// How long a client gets to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Serves the metrics of `resolver` in the Prometheus text format on `/metrics` to every
+/// connection on `listener`. There's no need for a full http server to answer a scrape every few
+/// seconds, so this only understands `GET` and closes the connection after every response.
+pub(crate) async fn serve(mut listener: TcpListener, resolver: Arc<Resolver>) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                warn!("error accepting a metrics connection: {}", e);
+                continue;
+            }
+        };
+        let resolver = Arc::clone(&resolver);
+        tokio::spawn(async move {
+            if let Err(e) = respond(stream, &resolver).await {
+                warn!("error serving the metrics: {}", e);
+            }
+        });
+    }
+}
+
+async fn respond(mut stream: TcpStream, resolver: &Resolver) -> crate::Result<()> {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < MAX_REQUEST {
+        let read = tokio::time::timeout(REQUEST_TIMEOUT, stream.read(&mut buf)).await??;
+        if read == 0 {
+            break;
+        }
+        request.extend_from_slice(&buf[..read]);
+    }
+
+    let request = String::from_utf8_lossy(&request);
+    let mut parts = request.split_whitespace();
+    let response = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            let body = resolver.metrics().await;
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+        }
+        (Some("GET"), _) => {
+            "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_owned()
+        }
+        _ => "HTTP/1.1 405 Method Not Allowed\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+            .to_owned(),
+    };
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown(std::net::Shutdown::Write)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::ResolveResponse;
+    use crate::stats::Stats;
+    use std::collections::VecDeque;
+    use std::net::IpAddr;
+
+    async fn get(addr: std::net::SocketAddr, request: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn the_stats_are_rendered_in_the_prometheus_text_format() {
+        let stats = Stats::default();
+        stats.host_done();
+        stats.queries(3);
+        let mut responses = VecDeque::new();
+        responses.push_back(ResolveResponse::Error {
+            query: "www.example.com.".to_owned(),
+            response_code: "Server Failure".to_owned(),
+            authority: None,
+            subnet: None,
+        });
+        stats.lookup(&responses).await;
+        let resolver = IpAddr::from([192, 0, 2, 1]);
+        stats.latency(resolver, Duration::from_millis(4)).await;
+        stats.latency(resolver, Duration::from_millis(300)).await;
+        stats.records_queue.add(7);
+
+        let expected = r#"# HELP ree_hosts_total Hosts that were resolved.
+# TYPE ree_hosts_total counter
+ree_hosts_total 1
+# HELP ree_queries_total Queries that were sent.
+# TYPE ree_queries_total counter
+ree_queries_total 3
+# HELP ree_timeouts_total Lookups that got no response.
+# TYPE ree_timeouts_total counter
+ree_timeouts_total 2
+# HELP ree_retries_total Queries the raw engine sent again.
+# TYPE ree_retries_total counter
+ree_retries_total 1
+# HELP ree_lookups_total Lookups that were answered, by response code.
+# TYPE ree_lookups_total counter
+ree_lookups_total{rcode="Server Failure"} 1
+# HELP ree_resolver_latency_seconds How long each resolver took to answer.
+# TYPE ree_resolver_latency_seconds histogram
+ree_resolver_latency_seconds_bucket{resolver="192.0.2.1",le="0.001"} 0
+ree_resolver_latency_seconds_bucket{resolver="192.0.2.1",le="0.002"} 0
+ree_resolver_latency_seconds_bucket{resolver="192.0.2.1",le="0.005"} 1
+ree_resolver_latency_seconds_bucket{resolver="192.0.2.1",le="0.01"} 1
+ree_resolver_latency_seconds_bucket{resolver="192.0.2.1",le="0.025"} 1
+ree_resolver_latency_seconds_bucket{resolver="192.0.2.1",le="0.05"} 1
+ree_resolver_latency_seconds_bucket{resolver="192.0.2.1",le="0.1"} 1
+ree_resolver_latency_seconds_bucket{resolver="192.0.2.1",le="0.25"} 1
+ree_resolver_latency_seconds_bucket{resolver="192.0.2.1",le="0.5"} 2
+ree_resolver_latency_seconds_bucket{resolver="192.0.2.1",le="1"} 2
+ree_resolver_latency_seconds_bucket{resolver="192.0.2.1",le="2.5"} 2
+ree_resolver_latency_seconds_bucket{resolver="192.0.2.1",le="5"} 2
+ree_resolver_latency_seconds_bucket{resolver="192.0.2.1",le="10"} 2
+ree_resolver_latency_seconds_bucket{resolver="192.0.2.1",le="+Inf"} 2
+ree_resolver_latency_seconds_sum{resolver="192.0.2.1"} 0.304
+ree_resolver_latency_seconds_count{resolver="192.0.2.1"} 2
+# HELP ree_queue_depth Messages waiting in the channel between the lookups and the results.
+# TYPE ree_queue_depth gauge
+ree_queue_depth{queue="records"} 7
+"#;
+        assert_eq!(stats.prometheus((1, 2)).await, expected);
+    }
+
+    #[tokio::test]
+    async fn only_get_requests_for_the_metrics_are_answered() {
+        let listener = TcpListener::bind(std::net::SocketAddr::from(([127, 0, 0, 1], 0)))
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, Arc::new(Resolver::default())));
+
+        let response = get(addr, "GET /metrics HTTP/1.1\r\nHost: ree\r\n\r\n").await;
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(head.contains(&format!("Content-Length: {}\r\n", body.len())));
+        assert!(body.contains("\nree_hosts_total 0\n"));
+
+        let response = get(addr, "GET / HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        let response = get(addr, "POST /metrics HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+    }
+}
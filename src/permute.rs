@@ -0,0 +1,239 @@
+use crate::suffix;
+use std::collections::HashSet;
+
+/// Environment prefixes and common words that are swapped into or inserted around resolved
+/// names. A user supplied word list is added on top of these.
+const DEFAULT_WORDS: &[&str] = &[
+    "dev",
+    "development",
+    "stage",
+    "staging",
+    "test",
+    "testing",
+    "qa",
+    "uat",
+    "prod",
+    "production",
+    "preprod",
+    "internal",
+    "admin",
+    "api",
+    "beta",
+    "demo",
+    "old",
+    "new",
+];
+
+/// The `Permutator` generates new candidates from names that are known to resolve, by inserting
+/// words around them, swapping the words they already contain and incrementing their numbers.
+#[derive(Debug)]
+pub struct Permutator {
+    words: Vec<String>,
+}
+
+impl Default for Permutator {
+    fn default() -> Self {
+        Self {
+            words: DEFAULT_WORDS.iter().map(|w| w.to_string()).collect(),
+        }
+    }
+}
+
+impl Permutator {
+    /// Adds the words in the file at `path` to the default set of words.
+    pub fn load_words(mut self, path: &str) -> Self {
+        let file = std::fs::read_to_string(path).expect("unable to read permutation words");
+        for word in file.lines().map(|l| l.trim().to_lowercase()) {
+            if !word.is_empty() && !self.words.contains(&word) {
+                self.words.push(word);
+            }
+        }
+        self
+    }
+
+    /// Returns every candidate generated from `names`. Names are expected without the trailing
+    /// dot, and the candidates won't have one either.
+    pub(crate) fn generate<'a, I>(&self, names: I) -> HashSet<String>
+    where
+        I: IntoIterator<Item = &'a String>,
+    {
+        let mut candidates = HashSet::new();
+        for name in names {
+            self.permute(&name.to_lowercase(), &mut candidates);
+        }
+        candidates
+    }
+
+    fn permute(&self, name: &str, candidates: &mut HashSet<String>) {
+        // Names under a public suffix belong to whoever registered them, so nothing is
+        // generated from the suffix itself.
+        if suffix::is_public_suffix(name) {
+            return;
+        }
+
+        // Inserting a new label in front of the name can't leave the domain.
+        for word in &self.words {
+            candidates.insert(format!("{}.{}", word, name));
+        }
+
+        // The first label is only changed if the parent isn't a public suffix, otherwise
+        // `example.co.uk` would turn into `dev-example.co.uk`, which is someone else's domain.
+        let (label, parent) = match name.split_once('.') {
+            Some((label, parent)) if !suffix::is_public_suffix(parent) => (label, parent),
+            _ => return,
+        };
+
+        for word in &self.words {
+            candidates.insert(format!("{}-{}.{}", word, label, parent));
+            candidates.insert(format!("{}-{}.{}", label, word, parent));
+            candidates.insert(format!("{}.{}.{}", label, word, parent));
+        }
+
+        // Swap any dash separated part of the label that is one of our words for the others.
+        let parts: Vec<&str> = label.split('-').collect();
+        for (i, part) in parts.iter().enumerate() {
+            if !self.words.iter().any(|w| w == part) {
+                continue;
+            }
+            for word in self.words.iter().filter(|w| w != part) {
+                let mut swapped = parts.clone();
+                swapped[i] = word;
+                candidates.insert(format!("{}.{}", swapped.join("-"), parent));
+            }
+        }
+
+        for incremented in increments(label) {
+            candidates.insert(format!("{}.{}", incremented, parent));
+        }
+
+        candidates.remove(name);
+    }
+}
+
+/// Returns the label with each run of digits incremented and decremented by one, keeping any
+/// zero padding, e.g `web02` becomes `web01` and `web03`.
+fn increments(label: &str) -> Vec<String> {
+    let mut labels = Vec::new();
+    let bytes = label.as_bytes();
+    let mut start = 0;
+
+    while start < bytes.len() {
+        if !bytes[start].is_ascii_digit() {
+            start += 1;
+            continue;
+        }
+
+        let mut end = start;
+        while end < bytes.len() && bytes[end].is_ascii_digit() {
+            end += 1;
+        }
+
+        let digits = &label[start..end];
+        if let Ok(n) = digits.parse::<u64>() {
+            let width = digits.len();
+            let mut numbers = vec![n.saturating_add(1)];
+            if n > 0 {
+                numbers.push(n - 1);
+            }
+            for number in numbers {
+                labels.push(format!(
+                    "{}{:0width$}{}",
+                    &label[..start],
+                    number,
+                    &label[end..],
+                    width = width
+                ));
+            }
+        }
+        start = end;
+    }
+
+    labels
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn increments_keep_the_zero_padding() {
+        assert_eq!(increments("web02"), vec!["web03", "web01"]);
+        assert_eq!(increments("web09"), vec!["web10", "web08"]);
+        assert_eq!(increments("web99"), vec!["web100", "web98"]);
+    }
+
+    #[test]
+    fn increments_every_run_of_digits() {
+        assert_eq!(
+            increments("db1-eu2"),
+            vec!["db2-eu2", "db0-eu2", "db1-eu3", "db1-eu1"]
+        );
+    }
+
+    #[test]
+    fn zero_is_not_decremented() {
+        assert_eq!(increments("node0"), vec!["node1"]);
+        assert_eq!(increments("node00"), vec!["node01"]);
+    }
+
+    #[test]
+    fn numbers_that_overflow_are_left_alone() {
+        assert_eq!(
+            increments("a18446744073709551615"),
+            vec!["a18446744073709551615", "a18446744073709551614"]
+        );
+        assert!(increments("a99999999999999999999999").is_empty());
+    }
+
+    #[test]
+    fn labels_without_digits_have_no_increments() {
+        assert!(increments("www").is_empty());
+        assert!(increments("").is_empty());
+    }
+
+    #[test]
+    fn the_apex_is_only_prefixed() {
+        let permutator = Permutator::default();
+        let candidates = permutator.generate(&["example.com".to_owned()]);
+        assert!(candidates.contains("dev.example.com"));
+        assert!(candidates.iter().all(|c| c.ends_with(".example.com")));
+        assert_eq!(candidates.len(), DEFAULT_WORDS.len());
+    }
+
+    #[test]
+    fn domains_under_multi_label_suffixes_are_only_prefixed() {
+        let permutator = Permutator::default();
+        let candidates = permutator.generate(&["example.co.uk".to_owned()]);
+        assert!(candidates.contains("dev.example.co.uk"));
+        assert!(!candidates.contains("dev-example.co.uk"));
+        assert!(candidates.iter().all(|c| c.ends_with(".example.co.uk")));
+        assert_eq!(candidates.len(), DEFAULT_WORDS.len());
+
+        let candidates = permutator.generate(&["api.example.com.au".to_owned()]);
+        assert!(candidates.contains("api-dev.example.com.au"));
+        assert!(candidates.iter().all(|c| c.ends_with(".example.com.au")));
+    }
+
+    #[test]
+    fn public_suffixes_are_not_permuted() {
+        let permutator = Permutator::default();
+        assert!(permutator.generate(&["co.uk".to_owned()]).is_empty());
+    }
+
+    #[test]
+    fn subdomains_are_permuted() {
+        let permutator = Permutator::default();
+        let candidates = permutator.generate(&["API-dev.example.com".to_owned()]);
+        assert!(candidates.contains("api-prod.example.com"));
+        assert!(candidates.contains("test-api-dev.example.com"));
+        assert!(candidates.contains("api-dev-qa.example.com"));
+        assert!(candidates.contains("api-dev.qa.example.com"));
+        assert!(candidates.contains("beta.api-dev.example.com"));
+        assert!(!candidates.contains("api-dev.example.com"));
+
+        let candidates = permutator.generate(&["web01.example.com".to_owned()]);
+        assert!(candidates.contains("web02.example.com"));
+        assert!(candidates.contains("web00.example.com"));
+        assert!(candidates.iter().all(|c| c.ends_with(".example.com")));
+    }
+}
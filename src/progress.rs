@@ -0,0 +1,196 @@
+use crate::stats::Stats;
+use std::collections::HashMap;
+use std::io::{IsTerminal, Write};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use trust_dns_proto::op::ResponseCode;
+
+/// How often the progress line is redrawn.
+const REFRESH: Duration = Duration::from_millis(500);
+
+/// A resolver counts as active if it answered one of the queries this recently.
+const ACTIVE_WINDOW: Duration = Duration::from_secs(5);
+
+/// The progress line shown on stderr while the hosts are resolved. The counts come from the
+/// `Stats`, taken from where they were at the start of every run.
+#[derive(Debug, Default)]
+pub(crate) struct Progress {
+    total: AtomicUsize,
+    hosts: AtomicUsize,
+    queries: AtomicUsize,
+    answered: Mutex<HashMap<IpAddr, Instant>>,
+}
+
+impl Progress {
+    /// Starts over for a run of `total` hosts, or an unknown number of them if it's 0.
+    pub(crate) async fn start(&self, total: usize, stats: &Stats) {
+        self.total.store(total, Ordering::Relaxed);
+        self.hosts.store(stats.hosts(), Ordering::Relaxed);
+        self.queries.store(stats.sent(), Ordering::Relaxed);
+        self.answered.lock().await.clear();
+    }
+
+    /// Records that `resolver` answered a query, whatever the answer was.
+    pub(crate) async fn answered(&self, resolver: IpAddr) {
+        self.answered.lock().await.insert(resolver, Instant::now());
+    }
+
+    /// Redraws the progress line until `done` resolves, as long as stderr is a terminal.
+    pub(crate) async fn show<F>(&self, stats: &Stats, resolvers: usize, done: F)
+    where
+        F: std::future::Future<Output = ()>,
+    {
+        if !std::io::stderr().is_terminal() {
+            return done.await;
+        }
+
+        let started = Instant::now();
+        let mut interval = tokio::time::interval(REFRESH);
+        futures::pin_mut!(done);
+        loop {
+            tokio::select! {
+                _ = &mut done => break,
+                _ = interval.tick() => {
+                    let line = self.line(stats, started.elapsed(), resolvers).await;
+                    eprint!("\r\x1b[2K{}", line);
+                    let _ = std::io::stderr().flush();
+                }
+            }
+        }
+        eprint!("\r\x1b[2K");
+    }
+
+    /// e.g `1200/5000 hosts, 850 queries/s, 4.1% errors (Server Failure 49), 8/8 resolvers,
+    /// ETA 4s`.
+    async fn line(&self, stats: &Stats, elapsed: Duration, resolvers: usize) -> String {
+        let total = self.total.load(Ordering::Relaxed);
+        let hosts = stats.hosts() - self.hosts.load(Ordering::Relaxed);
+        let queries = stats.sent() - self.queries.load(Ordering::Relaxed);
+        let seconds = elapsed.as_secs_f64().max(0.001);
+
+        let mut line = if total > 0 {
+            format!("{}/{} hosts", hosts, total)
+        } else {
+            format!("{} hosts", hosts)
+        };
+        line += &format!(", {:.0} queries/s", queries as f64 / seconds);
+
+        let lookups = stats.lookups();
+        if lookups > 0 {
+            line += &format!(
+                ", {:.1}% errors",
+                stats.errors() as f64 / lookups as f64 * 100.0
+            );
+            let no_error = ResponseCode::NoError.to_string();
+            let rcodes: Vec<String> = stats
+                .rcodes()
+                .await
+                .iter()
+                .filter(|(rcode, _)| **rcode != no_error)
+                .map(|(rcode, count)| format!("{} {}", rcode, count))
+                .collect();
+            if !rcodes.is_empty() {
+                line += &format!(" ({})", rcodes.join(", "));
+            }
+        }
+
+        let active = self
+            .answered
+            .lock()
+            .await
+            .values()
+            .filter(|answered| answered.elapsed() < ACTIVE_WINDOW)
+            .count();
+        line += &format!(", {}/{} resolvers", active, resolvers);
+
+        if total > hosts && hosts > 0 {
+            let remaining = (total - hosts) as f64 / (hosts as f64 / seconds);
+            line += &format!(", ETA {}", duration(remaining as u64));
+        }
+        line
+    }
+}
+
+/// Formats `seconds` like `1h2m3s`, leaving out the units that are 0 at the front.
+fn duration(seconds: u64) -> String {
+    let (hours, minutes, seconds) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+    if hours > 0 {
+        format!("{}h{}m{}s", hours, minutes, seconds)
+    } else if minutes > 0 {
+        format!("{}m{}s", minutes, seconds)
+    } else {
+        format!("{}s", seconds)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::ResolveResponse;
+    use std::collections::VecDeque;
+
+    async fn lookup(stats: &Stats, rcode: ResponseCode) {
+        let mut responses = VecDeque::new();
+        responses.push_back(ResolveResponse::Error {
+            query: "www.example.com.".to_owned(),
+            response_code: rcode.to_string(),
+            authority: None,
+            subnet: None,
+        });
+        stats.lookup(&responses).await;
+    }
+
+    #[tokio::test]
+    async fn the_rate_and_eta_count_from_the_start_of_the_run() {
+        let (stats, progress) = (Stats::default(), Progress::default());
+        stats.host_done();
+        stats.queries(20);
+        progress.start(10, &stats).await;
+
+        for _ in 0..4 {
+            stats.host_done();
+        }
+        stats.queries(60);
+        for rcode in [
+            ResponseCode::NoError,
+            ResponseCode::NoError,
+            ResponseCode::ServFail,
+        ] {
+            lookup(&stats, rcode).await;
+        }
+        lookup(&stats, ResponseCode::NoError).await;
+        progress.answered("192.0.2.1".parse().unwrap()).await;
+
+        // 4 hosts in 2s leaves 6 for another 3s.
+        assert_eq!(
+            progress.line(&stats, Duration::from_secs(2), 2).await,
+            "4/10 hosts, 30 queries/s, 25.0% errors (Server Failure 1), 1/2 resolvers, ETA 3s"
+        );
+    }
+
+    #[tokio::test]
+    async fn there_is_no_eta_without_a_total_or_a_rate() {
+        let (stats, progress) = (Stats::default(), Progress::default());
+        progress.start(10, &stats).await;
+        assert_eq!(
+            progress.line(&stats, Duration::from_secs(2), 1).await,
+            "0/10 hosts, 0 queries/s, 0/1 resolvers"
+        );
+
+        progress.start(0, &stats).await;
+        stats.host_done();
+        assert_eq!(
+            progress.line(&stats, Duration::from_secs(2), 1).await,
+            "1 hosts, 0 queries/s, 0/1 resolvers"
+        );
+    }
+
+    #[test]
+    fn durations_leave_out_the_leading_units_that_are_0() {
+        assert_eq!(duration(3723), "1h2m3s");
+        assert_eq!(duration(120), "2m0s");
+        assert_eq!(duration(59), "59s");
+    }
+}
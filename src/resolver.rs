@@ -1,31 +1,62 @@
+use crate::authority::{self, Authorities};
+use crate::axfr;
+use crate::cache::AnswerCache;
+use crate::clients::{Client, Clients};
+use crate::cloud::CloudRanges;
 use crate::data::{ResolveResponse, ResultsCache};
-use crate::OutputFormat;
-use crate::Result;
+use crate::dnssec;
+use crate::engine::{RawEngine, SocketOptions};
+use crate::input::Wordlist;
+use crate::metrics;
+use crate::permute::Permutator;
+use crate::progress::Progress;
+use crate::shutdown::{Shutdown, DRAIN_TIMEOUT};
+use crate::state::Checkpoint;
+use crate::stats::Stats;
+use crate::takeover::Fingerprints;
+use crate::walk::{self, Walk};
+use crate::wire;
+use crate::{Engine, OutputFormat, WildcardMode};
+use crate::{ReeError, Result};
 use futures::StreamExt;
-use std::collections::VecDeque;
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use ipnet::IpNet;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::ops::RangeInclusive;
 use std::path::PathBuf;
-use std::sync::Arc;
-use std::time::Duration;
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
 use tokio::fs;
 use tokio::sync::mpsc::{channel, Receiver, Sender};
 use tracing::{info, warn};
+use trust_dns_proto::op::{Query, ResponseCode};
+use trust_dns_proto::rr::Record;
 use trust_dns_proto::{rr::record_type::RecordType, xfer::dns_request::DnsRequestOptions};
 use trust_dns_resolver::{
     config::LookupIpStrategy, config::NameServerConfigGroup, config::ResolverConfig,
-    config::ResolverOpts, error::ResolveError, lookup::Lookup, lookup_ip::LookupIp,
-    TokioAsyncResolver,
+    config::ResolverOpts, error::ResolveError, error::ResolveErrorKind, lookup::Lookup,
+    lookup_ip::LookupIp,
 };
 
 // The maximum number of messages that can be in the channel before calls to .send start waiting
 // for the receiver to take from the channel.
 const CHAN_SIZE: usize = 32 * 4;
-const CANARY: &str = "cmVlc29sdmVjYW5hcnk";
+// The number of random labels looked up under a zone to find out what its wildcard answers with.
+const CANARIES: usize = 3;
+// The number of responses the results are written into the cache in, and the longest they wait.
+const BATCH_SIZE: usize = 256;
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+// How often the hosts that finished are checkpointed to the state directory.
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(10);
 
-/// `Lookup` for general records, and `LookupIp` for A & AAAA records.
-enum Lookups {
-    Lookup(std::result::Result<Lookup, ResolveError>),
-    LookupIp(std::result::Result<LookupIp, ResolveError>),
+/// What the lookups of the hosts send down the pipeline to `cache_responses`.
+#[derive(Debug)]
+enum Output {
+    /// The responses to some of the queries of a host.
+    Responses(VecDeque<ResolveResponse>),
+    /// Sent by a host after all of its responses, so it's only completed in the state directory
+    /// once they've been saved.
+    Finished(String),
 }
 
 /// The `Resolver` struct is responsible for storing configuration details
@@ -33,10 +64,32 @@ enum Lookups {
 pub struct Resolver {
     config: ResolverConfig,
     options: ResolverOpts,
-    nameservers: Vec<IpAddr>,
+    nameservers: Vec<SocketAddr>,
     output_format: OutputFormat,
     output_path: PathBuf,
     stdout: bool,
+    permutator: Option<Permutator>,
+    fingerprints: Option<Fingerprints>,
+    cloud_ranges: CloudRanges,
+    canaries: Vec<String>,
+    wildcard_mode: WildcardMode,
+    authoritative: bool,
+    dnssec: bool,
+    subnets: Vec<IpNet>,
+    answers: AnswerCache,
+    clients: Clients,
+    engine: Engine,
+    raw: OnceLock<RawEngine>,
+    sockets: SocketOptions,
+    state_dir: Option<PathBuf>,
+    resume: bool,
+    checkpoint: Option<Arc<Checkpoint>>,
+    shutdown: Shutdown,
+    progress: Progress,
+    stats: Stats,
+    metrics_addr: Option<SocketAddr>,
+    batch_size: usize,
+    flush_interval: Duration,
 }
 
 impl Default for Resolver {
@@ -52,7 +105,10 @@ impl Default for Resolver {
             IpAddr::V4(Ipv4Addr::new(1, 0, 0, 1)),
             IpAddr::V6(Ipv6Addr::new(0x2606, 0x4700, 0x4700, 0, 0, 0, 0, 0x1111)),
             IpAddr::V6(Ipv6Addr::new(0x2606, 0x4700, 0x4700, 0, 0, 0, 0, 0x1001)),
-        ];
+        ]
+        .into_iter()
+        .map(|ip| SocketAddr::new(ip, 53))
+        .collect();
 
         Resolver {
             config: ResolverConfig::cloudflare(),
@@ -78,6 +134,28 @@ impl Default for Resolver {
             output_format: OutputFormat::Json,
             output_path: PathBuf::default(),
             stdout: false,
+            permutator: None,
+            fingerprints: None,
+            cloud_ranges: CloudRanges::default(),
+            canaries: (0..CANARIES).map(|_| canary()).collect(),
+            wildcard_mode: WildcardMode::Flag,
+            authoritative: false,
+            dnssec: false,
+            subnets: Vec::new(),
+            answers: AnswerCache::default(),
+            clients: Clients::default(),
+            engine: Engine::Resolver,
+            raw: OnceLock::new(),
+            sockets: SocketOptions::default(),
+            state_dir: None,
+            resume: false,
+            checkpoint: None,
+            shutdown: Shutdown::default(),
+            progress: Progress::default(),
+            stats: Stats::default(),
+            metrics_addr: None,
+            batch_size: BATCH_SIZE,
+            flush_interval: FLUSH_INTERVAL,
         }
     }
 }
@@ -95,280 +173,1618 @@ impl Resolver {
         self
     }
 
+    /// Builder method that sets what happens to wildcard records, `keep` skips the detection
+    /// entirely, `flag` marks them, `drop` discards them and `only` discards everything else.
+    /// Defaults to `flag`.
+    pub fn wildcard(mut self, mode: &str) -> Self {
+        self.wildcard_mode = match mode {
+            "keep" => WildcardMode::Keep,
+            "drop" => WildcardMode::Drop,
+            "only" => WildcardMode::Only,
+            _ => WildcardMode::Flag,
+        };
+        self
+    }
+
+    /// Builder method that sends the queries to the authoritative nameservers of each host's zone
+    /// instead of the resolvers, and reports the answers of each nameserver separately.
+    pub fn authoritative(mut self) -> Self {
+        self.authoritative = true;
+        self
+    }
+
+    /// Builder method that validates the answers of each host with DNSSEC, and reports which of
+    /// the zones the hosts are in are signed.
+    pub fn dnssec(mut self) -> Self {
+        self.dnssec = true;
+        self
+    }
+
+    /// Builder method that sends the EDNS Client Subnet option with the queries, once for each of
+    /// the `subnets`, so the answers CDNs and GeoDNS give to clients in each of them can be
+    /// compared. Every answer records the subnet it was given for.
+    pub fn subnets(mut self, subnets: Vec<IpNet>) -> Self {
+        self.subnets = subnets;
+        self
+    }
+
+    /// Builder method that sets the engine the queries are sent with. `resolver` (the default)
+    /// uses the resolver library, `raw` sends them over a small pool of udp sockets without any of
+    /// its overhead, which is a lot faster for large lists of hosts.
+    pub fn engine(mut self, engine: &str) -> Self {
+        self.engine = match engine {
+            "raw" => Engine::Raw,
+            _ => Engine::Resolver,
+        };
+        self
+    }
+
+    /// Builder method that sets the local address the queries are sent from. Only nameservers of
+    /// the same address family can be reached once it's set. Like the other socket options, this
+    /// needs the raw engine, the resolver library always picks its own sockets.
+    pub fn bind_address(mut self, address: IpAddr) -> Self {
+        self.sockets.bind = Some(address);
+        self
+    }
+
+    /// Builder method that limits the source ports of the queries to `ports`.
+    pub fn source_ports(mut self, ports: RangeInclusive<u16>) -> Self {
+        self.sockets.ports = Some(ports);
+        self
+    }
+
+    /// Builder method that sets how many udp sockets the queries are spread over, for each address
+    /// family. Defaults to 8.
+    pub fn sockets(mut self, sockets: usize) -> Self {
+        self.sockets.sockets = Some(sockets);
+        self
+    }
+
+    /// Builder method that checkpoints the completed hosts and their responses to `dir` while
+    /// running, so an interrupted run can be resumed.
+    pub fn state_dir(mut self, dir: PathBuf) -> Self {
+        self.state_dir = Some(dir);
+        self
+    }
+
+    /// Builder method that resumes the run checkpointed to the state directory, skipping the
+    /// hosts it already completed.
+    pub fn resume(mut self) -> Self {
+        self.resume = true;
+        self
+    }
+
+    /// Builder method that serves the metrics of the run in the Prometheus format on `addr`.
+    pub fn metrics_addr(mut self, addr: SocketAddr) -> Self {
+        self.metrics_addr = Some(addr);
+        self
+    }
+
+    /// Builder method that sets how many responses are written into the results at once.
+    /// Defaults to 256
+    pub fn batch_size(mut self, size: usize) -> Self {
+        self.batch_size = size.max(1);
+        self
+    }
+
+    /// Builder method that sets the longest the responses wait to be written into the results,
+    /// when there aren't enough of them for a batch. Defaults to 1 second
+    pub fn flush_interval(mut self, interval: Duration) -> Self {
+        self.flush_interval = interval.max(Duration::from_millis(1));
+        self
+    }
+
     /// Builder method that sets the timeout for the request. Defaults to 5 seconds
     pub fn timeout(mut self, timeout: u64) -> Self {
         self.options.timeout = Duration::from_secs(timeout);
         self
     }
 
-    /// Loads a list of custom resolvers (nameservers) into the resolver config. Default set of
-    /// resolvers is Google and CloudFlare.
-    pub fn load_resolvers(mut self, path: &str) -> Self {
-        let file = std::fs::read_to_string(path).unwrap();
-        let ips: Vec<IpAddr> = file.lines().map(|l| l.parse::<IpAddr>().unwrap()).collect();
-        let group = NameServerConfigGroup::from_ips_clear(&ips, 53, false);
-        self.config = ResolverConfig::from_parts(None, vec![], group);
-        self.nameservers = ips;
+    /// Builder method that enables rounds of permutations on the resolved names once the hosts
+    /// have been resolved.
+    pub fn permute(mut self, permutator: Permutator) -> Self {
+        self.permutator = Some(permutator);
         self
     }
 
-    /// Handles extracting the records or the errors from the dns query and sends it down the
-    /// channel. The receiver handles caching the responses before serializing them.
-    async fn deliver_response(
-        mut records_sender: Sender<VecDeque<ResolveResponse>>,
-        lookup: Lookups,
-    ) -> Result<()> {
-        //TODO: Should probably only send across the channel once the VecDeque reaches a certain
-        // capacity !
-        let mut records: VecDeque<ResolveResponse> = VecDeque::new();
-
-        match lookup {
-            Lookups::Lookup(result) => match result {
-                Ok(r) => {
-                    let query = Arc::new(r.query().name().to_utf8());
-                    records.extend(r.record_iter().map(|record| {
-                        info!("got {:?}", record);
-                        ResolveResponse::new(record, Arc::clone(&query))
-                    }));
-
-                    records_sender.send(records).await?;
-                }
+    /// Builder method that enables checking the CNAME records for possible subdomain takeovers.
+    pub fn takeover(mut self, fingerprints: Fingerprints) -> Self {
+        self.fingerprints = Some(fingerprints);
+        self
+    }
 
-                Err(e) => {
-                    push_error(e, records_sender).await?;
-                }
-            },
+    /// Builder method that sets the ranges used to tag addresses with their cloud provider.
+    /// Defaults to the bundled snapshot.
+    pub fn cloud_ranges(mut self, ranges: CloudRanges) -> Self {
+        self.cloud_ranges = ranges;
+        self
+    }
 
-            Lookups::LookupIp(result) => match result {
-                Ok(r) => {
-                    let query = Arc::new(r.as_lookup().query().name().to_utf8());
-                    records.extend(r.as_lookup().record_iter().map(|record| {
-                        info!("got {:?}", record);
-                        ResolveResponse::new(record, Arc::clone(&query))
-                    }));
+    /// Loads a list of custom resolvers (nameservers) into the resolver config, one address per
+    /// line with an optional port, e.g `1.1.1.1` or `127.0.0.1:5353`. Default set of resolvers is
+    /// Google and CloudFlare.
+    pub fn load_resolvers(self, path: &str) -> Self {
+        let file = std::fs::read_to_string(path).unwrap();
+        let nameservers: Vec<SocketAddr> = file
+            .lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty())
+            .map(|l| {
+                l.parse::<SocketAddr>()
+                    .or_else(|_| l.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, 53)))
+                    .unwrap_or_else(|_| panic!("invalid resolver {}", l))
+            })
+            .collect();
+        self.nameservers(nameservers)
+    }
 
-                    records_sender.send(records).await?;
-                }
+    /// Builder method that sends the queries to `nameservers`.
+    fn nameservers(mut self, nameservers: Vec<SocketAddr>) -> Self {
+        let mut group = NameServerConfigGroup::new();
+        for ns in &nameservers {
+            group.merge(NameServerConfigGroup::from_ips_clear(
+                &[ns.ip()],
+                ns.port(),
+                false,
+            ));
+        }
+        self.config = ResolverConfig::from_parts(None, vec![], group);
+        self.nameservers = nameservers;
+        self
+    }
 
-                Err(e) => {
-                    push_error(e, records_sender).await?;
-                }
-            },
+    /// Sends the responses of a lookup down the channel, the receiver handles caching them before
+    /// serializing them. Lookups that came back with nothing, like the ones that timed out, aren't
+    /// sent at all.
+    async fn deliver(
+        &self,
+        sender: &mut Sender<Output>,
+        responses: VecDeque<ResolveResponse>,
+    ) -> Result<()> {
+        if responses.is_empty() {
+            return Ok(());
         }
+        self.stats.records_queue.add(1);
+        sender.send(Output::Responses(responses)).await?;
         Ok(())
     }
 
-    /// Receives the records and adds them into a queue, when the queue is full it's contents will
-    /// be written into the `ResultsCache`
+    /// Receives the records and adds them into a queue, whose contents are written into the
+    /// `ResultsCache` once it holds `batch_size` responses, or every `flush_interval` so a slow
+    /// trickle of responses doesn't sit in the queue. When the run is checkpointed, the responses
+    /// are saved as they arrive, and the hosts that finished are completed every
+    /// `CHECKPOINT_INTERVAL`, which flushes the responses saved before them.
     async fn cache_responses(
-        mut receiver: Receiver<VecDeque<ResolveResponse>>,
-        mut queue_size: usize,
+        mut receiver: Receiver<Output>,
         cache: Arc<ResultsCache>,
-        total: usize,
+        resolver: Arc<Self>,
     ) {
-        let mut queue_count: usize = 0;
-
-        // If queue size is larger than the total, set it to the total.
-        if queue_size > total {
-            queue_size = total
-        }
-
         // Instead of writing to the `ResultsCache` each time we receieve a response, we only
-        // write when the queue contains `queue_size` number of responses. This is a neat little
-        // optimisation that will reduce the contention, because the lock is taken less often it
-        // will be faster to acquire.
-        let mut queue: VecDeque<ResolveResponse> = VecDeque::with_capacity(queue_size);
-        while let Some(mut records) = receiver.recv().await {
-            info!("added {} responses to the queue", records.len());
-            queue_count += records.len();
-            queue.append(&mut records);
-
-            // Queue is full, write results into the cache
-            if queue_count == queue_size {
-                info!("queue is full, writing {} responses to cache", queue.len());
-                let cache = Arc::clone(&cache);
-                cache.insert(&mut queue).await;
-                queue_count = 0;
+        // write in batches. This is a neat little optimisation that will reduce the contention,
+        // because the lock is taken less often it will be faster to acquire.
+        let mut queue: VecDeque<ResolveResponse> = VecDeque::with_capacity(resolver.batch_size);
+        let mut interval = tokio::time::interval(resolver.flush_interval);
+        // A host only finishes after every one of its responses came through here.
+        let mut finished = Vec::new();
+        let mut checkpoints = tokio::time::interval(CHECKPOINT_INTERVAL);
+        loop {
+            tokio::select! {
+                output = receiver.recv() => {
+                    let mut records = match output {
+                        Some(Output::Responses(records)) => records,
+                        Some(Output::Finished(host)) => {
+                            finished.push(host);
+                            continue;
+                        }
+                        None => break,
+                    };
+                    info!("added {} responses to the queue", records.len());
+                    resolver.stats.records_queue.sub(1);
+                    resolver.stats.lookup(&records).await;
+                    if let Some(checkpoint) = &resolver.checkpoint {
+                        if let Err(e) = checkpoint.save(&records).await {
+                            warn!("error checkpointing responses: {}", e);
+                        }
+                    }
+                    queue.append(&mut records);
+
+                    // A message can hold any number of responses, so the queue is usually
+                    // past the batch size rather than at it.
+                    if queue.len() >= resolver.batch_size {
+                        info!("queue is full, writing {} responses to cache", queue.len());
+                        cache.insert(&mut queue).await;
+                    }
+                }
+                _ = interval.tick() => {
+                    if !queue.is_empty() {
+                        info!("flushing {} responses to cache", queue.len());
+                        cache.insert(&mut queue).await;
+                    }
+                }
+                _ = checkpoints.tick() => resolver.complete(&mut finished).await,
             }
         }
 
         // If there is anything remaining in the queue than write it to the output file.
         if !queue.is_empty() {
             info!("caching the remaining contents of the queue");
-            let cache = Arc::clone(&cache);
             cache.insert(&mut queue).await;
         }
+        resolver.complete(&mut finished).await;
     }
 
-    /// Create a resolver for each name server, and then spawn a task for each one. This is required
+    /// Marks the `finished` hosts as completed in the state directory, if the run is checkpointed.
+    /// They're kept to try again next time if that fails.
+    async fn complete(&self, finished: &mut Vec<String>) {
+        let checkpoint = match &self.checkpoint {
+            Some(checkpoint) if !finished.is_empty() => checkpoint,
+            _ => return,
+        };
+        match checkpoint.complete(finished).await {
+            Ok(()) => finished.clear(),
+            Err(e) => warn!("error checkpointing completed hosts: {}", e),
+        }
+    }
+
+    /// Look up the target with the client of each name server separately. This is required
     /// because we want to retrieve the record even if two nameservers results conflict with each other. If
     /// we didn't care about retrieving conflicting records, we could just make one
-    /// `TokioAsyncResolver` with a `NameServerConfigGroup` containing all the nameservers
-    async fn enumerate_ns(&self, target: String, sender: Sender<Lookups>) {
-        // Instead of sending a single LookupIp across the channel each time, maybe we should
-        // instead send them in batches of Vec<LookupIp, ResolveError> ?
+    /// `Client` with a `NameServerConfigGroup` containing all the nameservers
+    async fn enumerate_ns(&self, target: String, sender: Sender<Output>) {
         let resolvers = self.nameservers.clone();
-        let tx = sender.clone();
         let results = futures::stream::iter(resolvers)
             .map(|ns| {
-                // Required clone; `resolver.lookup_ip(target)` won't allow arcs because of trait
-                // bounds adding `.` performs a faster query.
-                let target_cpy = target.clone() + ".";
-                let mut tx1 = tx.clone();
-                let group = NameServerConfigGroup::from_ips_clear(&[ns], 53, false);
-                let resolver = Arc::new(
-                    TokioAsyncResolver::tokio(
-                        ResolverConfig::from_parts(None, vec![], group),
-                        self.options,
-                    )
-                    .expect("error building resolver"),
-                );
-
-                // Push the lookup for A & AAAA records to it's own task
-                let resolver_cpy = Arc::clone(&resolver);
-                let t = target_cpy.clone();
-                tokio::spawn(async move {
-                    // https://docs.rs/trust-dns-resolver/0.20.0-alpha.2/trust_dns_resolver/struct.AsyncResolver.html#method.lookup_ip
-                    let resp = resolver_cpy.lookup_ip(t).await;
-                    tx1.send(Lookups::LookupIp(resp)).await
-                });
+                // adding `.` performs a faster query.
+                let target = target.clone() + ".";
+                let mut tx = sender.clone();
+                let resolver = self.clients.get(&self.nameservers, self.options, ns);
 
-                // Push the lookup for CNAME records to it's own task
-                let mut tx2 = tx.clone();
-                tokio::spawn(async move {
-                    let options = DnsRequestOptions {
-                        expects_multiple_responses: false,
-                        use_edns: false,
+                async move {
+                    let cached = self.answers.get(ns.ip(), &target, RecordType::CNAME);
+                    self.answers.record(cached.is_some());
+                    let (cname, ip) = match cached {
+                        // The addresses of its target might be cached too, then nothing has to be
+                        // sent at all.
+                        Some(cname) => {
+                            let ip = self.lookup_ip(ns, resolver, &target, Some(&cname)).await;
+                            (Ok(cname), Some(ip))
+                        }
+                        // Otherwise both go out at once, so the hosts that need both only wait
+                        // for the one round trip.
+                        None => {
+                            let (cname, ip) = futures::join!(
+                                self.lookup_cname(ns, resolver, &target),
+                                self.lookup_ip(ns, resolver, &target, None)
+                            );
+                            // The addresses of a host that doesn't exist are the same NXDOMAIN.
+                            let ip = (!is_nxdomain(&cname)).then_some(ip);
+                            (cname, ip)
+                        }
                     };
-                    let resp = resolver
-                        .lookup(target_cpy, RecordType::CNAME, options)
-                        .await;
-                    tx2.send(Lookups::Lookup(resp)).await
-                })
+                    // The responses are converted right here instead of in a task of their own,
+                    // so they only cross the one channel to the cache.
+                    if let Some(ip) = ip {
+                        self.deliver(&mut tx, lookup_ip_responses(ip)).await?;
+                    }
+                    self.deliver(&mut tx, lookup_responses(cname)).await
+                }
             })
             .buffer_unordered(32) // 32 nameservers at once
             .collect::<Vec<_>>();
         results.await;
     }
 
-    /// To detect wilcard we append a canary string to the results and do an A lookup for the
-    /// resulting record. If it returns a result, then the record is a wildcard.
-    async fn detect_wildcard(&self, cache: Arc<ResultsCache>, concurrency: usize) {
-        // Clone the HashMap in the cache so we don't have to hold the lock the entire duration of
-        // `detect_wildcard`
-        let map = cache.records().await;
-        let mut options = self.options;
-        options.ip_strategy = LookupIpStrategy::Ipv4thenIpv6;
-        let resolver = Arc::new(
-            TokioAsyncResolver::tokio(self.config.clone(), options)
-                .expect("error building resolver"),
-        );
+    /// Starts the raw engine when it's in use, so a bind address or port range that can't be
+    /// used fails the run before any host is looked up. The resolver library always picks its own
+    /// sockets, so the socket options are rejected without the raw engine.
+    fn start_engine(&self) -> Result<()> {
+        if self.engine != Engine::Raw {
+            if self.sockets.is_set() {
+                return Err(ReeError::Engine(
+                    "--bind, --source-ports and --sockets need --engine raw".to_owned(),
+                ));
+            }
+            return Ok(());
+        }
+        if self.raw.get().is_none() {
+            let engine = RawEngine::new(&self.sockets, self.options.timeout, self.options.attempts)
+                .map_err(|e| ReeError::Engine(format!("error starting the raw engine: {}", e)))?;
+            let _ = self.raw.set(engine);
+        }
+        Ok(())
+    }
+
+    /// The raw engine, started by `start_engine` before any host is looked up.
+    fn raw_engine(&self) -> &RawEngine {
+        self.raw.get().expect("the raw engine wasn't started")
+    }
+
+    /// Sends the queries for `target` to each of the resolvers with the raw engine.
+    async fn enumerate_raw(&self, target: String, mut sender: Sender<Output>) {
+        let engine = self.raw_engine();
+        let target = target.as_str();
+        let queries: Vec<_> = self
+            .nameservers
+            .iter()
+            .map(|ns| (ns.ip(), engine.lookup(*ns, target, true, None)))
+            .collect();
+        self.send_all(queries, &mut sender).await;
+    }
+
+    /// Sends the `queries` to their nameservers, 32 at a time, recording how long each nameserver
+    /// took to answer, and passes their answers on in order. Each query returns its answers and
+    /// how many queries it took, and only the ones that timed out come back without answers.
+    async fn send_all<F>(&self, queries: Vec<(IpAddr, F)>, sender: &mut Sender<Output>)
+    where
+        F: std::future::Future<Output = (VecDeque<ResolveResponse>, usize)>,
+    {
+        let queries = queries.into_iter().map(|(ns, query)| async move {
+            let started = Instant::now();
+            let (answers, sent) = query.await;
+            self.stats.queries(sent);
+            if !answers.is_empty() {
+                self.answered(ns, started).await;
+            }
+            answers
+        });
+        let mut responses = futures::stream::iter(queries)
+            .buffered(32)
+            .collect::<Vec<_>>()
+            .await;
 
-        let results = futures::stream::iter(map.into_iter().filter_map(|tup| match tup.1 {
-            ResolveResponse::IpRecord { name, .. } | ResolveResponse::Record { name, .. } => {
-                Some((tup.0, name))
+        for answers in responses.drain(..) {
+            if self.deliver(sender, answers).await.is_err() {
+                return;
             }
-            _ => None,
-        }))
-        .map(|(key, name)| {
-            let cache = Arc::clone(&cache);
-            let resolver = Arc::clone(&resolver);
-
-            tokio::spawn(async move {
-                let wildcard = format!("{}.{}", CANARY, name);
-                if resolver.lookup_ip(wildcard).await.is_ok() {
-                    info!("{} is wildcard record", &key);
-                    // Only acquire the lock if we've found a wildcard
-                    cache.set_wildcard(&key).await;
+        }
+    }
+
+    /// Looks up the CNAME records of `target` using the resolver at `ns`.
+    async fn lookup_cname(
+        &self,
+        ns: SocketAddr,
+        resolver: &Client,
+        target: &str,
+    ) -> std::result::Result<Lookup, ResolveError> {
+        let options = DnsRequestOptions {
+            expects_multiple_responses: false,
+            use_edns: false,
+        };
+        let started = Instant::now();
+        let lookup = resolver.lookup(target, RecordType::CNAME, options).await;
+        self.observe(ns, started, &lookup).await;
+        let lookup = lookup?;
+        self.answers.insert(ns.ip(), RecordType::CNAME, &lookup);
+        Ok(lookup)
+    }
+
+    /// Looks up the A and AAAA records of `target` using the resolver at `ns`. They come from the
+    /// cache when `target` itself is cached, or when the target of its `cname` is.
+    async fn lookup_ip(
+        &self,
+        ns: SocketAddr,
+        resolver: &Client,
+        target: &str,
+        cname: Option<&Lookup>,
+    ) -> std::result::Result<LookupIp, ResolveError> {
+        if let Some(lookup) = self.answers.get(ns.ip(), target, RecordType::A) {
+            self.answers.record(true);
+            return Ok(lookup.into());
+        }
+
+        if let Some(cname) = cname {
+            let alias = cname
+                .record_iter()
+                .find_map(|record| record.rdata().as_cname().cloned());
+            if let Some(alias) = alias {
+                if let Some(rest) = self.answers.get(ns.ip(), &alias.to_utf8(), RecordType::A) {
+                    self.answers.record(true);
+                    let records: Vec<Record> = cname
+                        .record_iter()
+                        .chain(rest.record_iter())
+                        .cloned()
+                        .collect();
+                    let query = Query::query(cname.query().name().clone(), RecordType::A);
+                    let valid_until = cname.valid_until().min(rest.valid_until());
+                    return Ok(
+                        Lookup::new_with_deadline(query, records.into(), valid_until).into(),
+                    );
                 }
+            }
+        }
+
+        self.answers.record(false);
+        let started = Instant::now();
+        let lookup = resolver.lookup_ip(target.to_owned()).await;
+        self.observe(ns, started, &lookup).await;
+        let lookup = lookup?;
+        self.answers
+            .insert(ns.ip(), RecordType::A, lookup.as_lookup());
+        Ok(lookup)
+    }
+
+    /// Counts a query sent to `ns` by the resolver library, and how long it took to answer if it
+    /// did.
+    async fn observe<T>(
+        &self,
+        ns: SocketAddr,
+        started: Instant,
+        lookup: &std::result::Result<T, ResolveError>,
+    ) {
+        self.stats.queries(1);
+        match lookup {
+            Ok(_) => self.answered(ns.ip(), started).await,
+            Err(e) => match e.kind() {
+                ResolveErrorKind::NoRecordsFound { .. } => self.answered(ns.ip(), started).await,
+                ResolveErrorKind::Timeout => self.stats.timeouts(1),
+                _ => {}
+            },
+        }
+    }
+
+    /// Records that `ns` answered a query that was sent at `started`.
+    async fn answered(&self, ns: IpAddr, started: Instant) {
+        self.stats.latency(ns, started.elapsed()).await;
+        self.progress.answered(ns).await;
+    }
+
+    /// Builds the resolver used for the canary lookups, only the addresses from one family are
+    /// needed to tell whether a name has a wildcard.
+    fn wildcard_resolver(&self) -> Client {
+        let mut options = self.options;
+        options.ip_strategy = LookupIpStrategy::Ipv4thenIpv6;
+        Client::new(self.config.clone(), options)
+    }
+
+    /// Sends the queries for `target` straight to each of the authoritative nameservers of its
+    /// zone instead of the resolvers, which could be serving stale answers from their caches.
+    async fn enumerate_authorities(
+        &self,
+        authorities: &Authorities,
+        target: String,
+        mut sender: Sender<Output>,
+    ) {
+        let nameservers = authorities.servers(&target).await;
+        let subnets = self.client_subnets();
+        let target = target.as_str();
+        let queries: Vec<_> = nameservers
+            .iter()
+            .flat_map(|ns| {
+                subnets.iter().map(move |subnet| {
+                    let query = authority::query(
+                        *ns,
+                        target,
+                        *subnet,
+                        self.options.timeout,
+                        self.raw.get(),
+                    );
+                    (*ns, query)
+                })
             })
-        })
-        .buffer_unordered(concurrency)
-        .collect::<Vec<_>>();
-        results.await;
+            .collect();
+        self.send_all(queries, &mut sender).await;
     }
 
-    /// The resolve method is responsible for enumerating all provided nameservers for all hosts.
-    /// Currently it does parallel Ipv4 & Ipv6 lookups for A and AAAA records and all of their
-    /// intermediate records. These records will then be cached before later being serialized into
-    /// either json or csv format.
-    pub async fn resolve(self, hosts: Vec<String>, concurrency: usize) -> Result<()> {
-        use tokio::prelude::*;
-        let total = hosts.len() * self.nameservers.len();
-        let cache = ResultsCache::new();
-        let resolver = Arc::new(self);
-        let queue_size: usize = 256;
-        let (lookup_sender, mut lookup_receiver) = channel::<Lookups>(CHAN_SIZE);
-        let (records_sender, records_receiver) = channel::<VecDeque<ResolveResponse>>(CHAN_SIZE);
+    /// Sends the queries for `target` to each of the resolvers once for every client subnet. The
+    /// resolver library can't send the EDNS Client Subnet option, so these go out over the wire
+    /// directly, through the raw engine when it's in use.
+    async fn enumerate_subnets(&self, target: String, mut sender: Sender<Output>) {
+        let target = target.as_str();
+        let queries: Vec<_> = self
+            .nameservers
+            .iter()
+            .flat_map(|&server| {
+                self.subnets.iter().map(move |subnet| {
+                    let query = async move {
+                        match self.engine {
+                            Engine::Raw => {
+                                self.raw_engine()
+                                    .lookup(server, target, true, Some(subnet))
+                                    .await
+                            }
+                            Engine::Resolver => {
+                                wire::lookup(
+                                    server,
+                                    target,
+                                    true,
+                                    Some(subnet),
+                                    self.options.timeout,
+                                )
+                                .await
+                            }
+                        }
+                    };
+                    (server.ip(), query)
+                })
+            })
+            .collect();
+        self.send_all(queries, &mut sender).await;
+    }
+
+    /// The subnets to send with each query, or a single `None` when no subnets were given so the
+    /// queries are still sent once.
+    fn client_subnets(&self) -> Vec<Option<&IpNet>> {
+        if self.subnets.is_empty() {
+            vec![None]
+        } else {
+            self.subnets.iter().map(Some).collect()
+        }
+    }
+
+    /// To detect wildcards we look up the random canaries under every parent zone of each query
+    /// to get the pool of answers those wildcards give out, e.g `api.dev.example.com` is checked
+    /// against `*.dev.example.com` and `*.example.com`. A host is only marked as a wildcard if all
+    /// of its answers are in one of those pools, so hosts that have a real answer that differs
+    /// from the wildcard's aren't marked. Returns the wildcard hosts and the zone of their
+    /// wildcard.
+    async fn detect_wildcard(
+        &self,
+        cache: Arc<ResultsCache>,
+        concurrency: usize,
+    ) -> HashMap<String, String> {
+        // Copy the answers out of the cache so we don't have to hold the lock the entire duration
+        // of `detect_wildcard`
+        let answers = cache.answers().await;
+        let resolver = self.wildcard_resolver();
+
+        let zones: HashSet<&str> = answers
+            .keys()
+            .flat_map(|query| parent_zones(query))
+            .collect();
+
+        // Each zone is only looked up once, no matter how many queries are under it.
+        let pools: HashMap<&str, HashSet<String>> = futures::stream::iter(zones)
+            .map(|zone| {
+                let resolver = &resolver;
+                async move { (zone, self.wildcard_pool(resolver, zone).await) }
+            })
+            .buffer_unordered(concurrency)
+            .filter(|(_, pool)| futures::future::ready(!pool.is_empty()))
+            .collect()
+            .await;
+
+        let wildcards: HashMap<String, String> = answers
+            .iter()
+            .filter_map(|(query, values)| {
+                // The closest zone wins, since that is the wildcard the query would have hit.
+                parent_zones(query)
+                    .find(|zone| pools.get(zone).is_some_and(|pool| values.is_subset(pool)))
+                    .map(|zone| {
+                        info!("{} is a wildcard host from *.{}", query, zone);
+                        (query.clone(), zone.to_owned())
+                    })
+            })
+            .collect();
+
+        // Only acquire the lock if we've found a wildcard
+        if !wildcards.is_empty() {
+            cache.set_wildcards(&wildcards).await;
+        }
+        self.stats.wildcards(&wildcards);
+        wildcards
+    }
+
+    /// Does an A lookup for each of the canaries under `zone`. Since the canaries should never
+    /// exist, anything that comes back is what the zone's wildcard answers with. The returned set
+    /// contains the values (addresses and CNAME targets) of those answers.
+    async fn wildcard_pool(&self, resolver: &Client, zone: &str) -> HashSet<String> {
+        let lookups = self.canaries.iter().map(|canary| async move {
+            let canary = Arc::new(format!("{}.{}.", canary, zone.trim_end_matches('.')));
+            match resolver.lookup_ip(canary.to_string()).await {
+                Ok(lookup) => lookup
+                    .as_lookup()
+                    .record_iter()
+                    .map(|record| ResolveResponse::new(record, Arc::clone(&canary)).key().2)
+                    .collect(),
+                Err(_) => Vec::new(),
+            }
+        });
+
+        futures::future::join_all(lookups)
+            .await
+            .into_iter()
+            .flatten()
+            .collect()
+    }
+
+    /// Runs every host through the lookup pipeline, writing the responses into `cache`.
+    async fn run<I>(
+        resolver: &Arc<Self>,
+        hosts: I,
+        concurrency: usize,
+        cache: &Arc<ResultsCache>,
+    ) -> Result<()>
+    where
+        I: IntoIterator<Item = String>,
+    {
+        let checkpoint = resolver.checkpoint.clone();
+        // Hosts completed by the run being resumed are skipped, their responses were loaded
+        // before starting.
+        let hosts = hosts.into_iter();
+        resolver
+            .progress
+            .start(hosts.size_hint().1.unwrap_or_default(), &resolver.stats)
+            .await;
+        let hosts = hosts.filter(|host| {
+            let completed = checkpoint.as_ref().is_some_and(|c| c.is_completed(host));
+            if completed {
+                resolver.stats.host_done();
+            }
+            !completed
+        });
+        let (records_sender, records_receiver) = channel::<Output>(CHAN_SIZE);
 
         // Handles storing the itermediate results before writing the final output to disk or
         // stdout
-        let cache_arc = Arc::clone(&cache);
+        let cache_arc = Arc::clone(cache);
+        let resolver_arc = Arc::clone(resolver);
         let output_manager = tokio::spawn(async move {
-            Resolver::cache_responses(records_receiver, queue_size, cache_arc, total).await
+            Resolver::cache_responses(records_receiver, cache_arc, resolver_arc).await
         });
 
-        // Recieves the responses and fires off a task to convert the `LookupIp` into our `Record`
-        // type and deliver it to the channel that will insert it into the `ResultsCache`
-        let response_manager = tokio::spawn(async move {
-            while let Some(response) = lookup_receiver.recv().await {
-                let records_sender = records_sender.clone();
-                // Push the handling of the responses off into their own tasks.
-                tokio::spawn(async move { Self::deliver_response(records_sender, response).await });
-            }
-        });
+        let authorities = if resolver.authoritative {
+            let client = Client::new(resolver.config.clone(), resolver.options);
+            Some(Arc::new(Authorities::new(client)))
+        } else {
+            None
+        };
 
-        // Iterate over each of the hosts and spawn a new task for each dns lookup
+        // Iterate over each of the hosts and spawn a new task for each dns lookup, until a
+        // shutdown is requested.
         let producer = futures::stream::iter(hosts)
+            .take_while(|_| futures::future::ready(resolver.shutdown.requested().is_none()))
             .map(|host| {
-                let resolver = Arc::clone(&resolver);
-                let lookup_sender = lookup_sender.clone();
-                tokio::spawn(async move { resolver.enumerate_ns(host, lookup_sender).await })
+                let resolver = Arc::clone(resolver);
+                let records_sender = records_sender.clone();
+                let authorities = authorities.clone();
+                let mut finished_sender = records_sender.clone();
+                tokio::spawn(async move {
+                    let finished = host.clone();
+                    let lookups = async {
+                        match authorities {
+                            Some(authorities) => {
+                                resolver
+                                    .enumerate_authorities(&authorities, host, records_sender)
+                                    .await
+                            }
+                            None if !resolver.subnets.is_empty() => {
+                                resolver.enumerate_subnets(host, records_sender).await
+                            }
+                            None if resolver.engine == Engine::Raw => {
+                                resolver.enumerate_raw(host, records_sender).await
+                            }
+                            None => resolver.enumerate_ns(host, records_sender).await,
+                        }
+                    };
+                    tokio::select! {
+                        _ = lookups => {}
+                        // Hosts that were cut off aren't completed, so resuming looks them up
+                        // again.
+                        _ = resolver.shutdown.drained() => return,
+                    }
+                    resolver.stats.host_done();
+                    if resolver.checkpoint.is_some() {
+                        let _ = finished_sender.send(Output::Finished(finished)).await;
+                    }
+                })
             })
             .buffer_unordered(concurrency)
             .collect::<Vec<_>>();
 
-        producer.await;
-        drop(lookup_sender);
-        response_manager.await?;
-        output_manager.await?;
-
+        let resolvers = resolver.nameservers.len();
         resolver
-            .detect_wildcard(Arc::clone(&cache), concurrency)
+            .progress
+            .show(&resolver.stats, resolvers, async {
+                producer.await;
+            })
             .await;
+        drop(records_sender);
+        output_manager.await?;
+        Ok(())
+    }
+
+    /// Generates permutations of every name that resolved and resolves them in another round,
+    /// which is repeated until a round doesn't turn up any new names. A candidate only counts as
+    /// new if some of its answers aren't the wildcard answers of its parent, otherwise a wildcard
+    /// would keep the rounds going forever.
+    async fn permutation_rounds(
+        resolver: &Arc<Self>,
+        permutator: &Permutator,
+        concurrency: usize,
+        cache: &Arc<ResultsCache>,
+    ) -> Result<()> {
+        let answers = cache.answers().await;
+        let mut seen: HashSet<String> = answers
+            .keys()
+            .map(|query| query.trim_end_matches('.').to_lowercase())
+            .collect();
+        let mut fresh = seen.clone();
+        let mut pools: HashMap<String, HashSet<String>> = HashMap::new();
+        let mut round = 0;
+
+        while resolver.shutdown.requested().is_none() {
+            let candidates: Vec<String> = permutator
+                .generate(&fresh)
+                .into_iter()
+                .filter(|candidate| seen.insert(candidate.clone()))
+                .collect();
+            if candidates.is_empty() {
+                break;
+            }
+            round += 1;
+            info!(
+                "permutation round {}: {} candidates",
+                round,
+                candidates.len()
+            );
+
+            let parents: HashSet<String> = candidates
+                .iter()
+                .filter_map(|c| c.split_once('.').map(|(_, parent)| parent.to_owned()))
+                .filter(|parent| !pools.contains_key(parent))
+                .collect();
+            let wildcard_resolver = resolver.wildcard_resolver();
+            let new_pools = futures::stream::iter(parents)
+                .map(|parent| {
+                    let wildcard_resolver = &wildcard_resolver;
+                    async move {
+                        let pool = resolver.wildcard_pool(wildcard_resolver, &parent).await;
+                        (parent, pool)
+                    }
+                })
+                .buffer_unordered(concurrency)
+                .collect::<Vec<_>>()
+                .await;
+            pools.extend(new_pools);
+
+            Self::run(resolver, candidates.clone(), concurrency, cache).await?;
+
+            let answers = cache.answers().await;
+            let empty = HashSet::new();
+            fresh = candidates
+                .into_iter()
+                .filter(|candidate| {
+                    let pool = candidate
+                        .split_once('.')
+                        .and_then(|(_, parent)| pools.get(parent))
+                        .unwrap_or(&empty);
+                    answers
+                        .get(&format!("{}.", candidate))
+                        .is_some_and(|keys| !keys.is_subset(pool))
+                })
+                .collect();
+        }
+
+        Ok(())
+    }
 
-        let results = cache.results(&resolver.output_format).await?;
-        if resolver.stdout {
+    /// Serializes the contents of the `ResultsCache` to stdout or the output file.
+    async fn write(&self, cache: &ResultsCache) -> Result<()> {
+        use tokio::prelude::*;
+        let results = cache.results(&self.output_format).await?;
+        if self.stdout {
             println!("{}", String::from_utf8_lossy(&results));
         } else {
-            let mut file = fs::File::create(&resolver.output_path).await?;
+            let mut file = fs::File::create(&self.output_path).await?;
             file.write_all(&results).await?;
+            // The write finishes in the background, which an interrupted run may not wait for.
+            file.flush().await?;
             println!(
                 "Done! {} records written to {:?}",
                 cache.num_results().await,
-                resolver.output_path
+                self.output_path
             );
+            if let Some(signal) = self.shutdown.requested() {
+                println!(
+                    "The run was interrupted by {}, not every host was resolved",
+                    signal
+                );
+            }
+            self.write_stats().await?;
+            let lookups = self.answers.hits() + self.answers.misses();
+            if lookups > 0 {
+                println!(
+                    "{} of {} lookups were answered from the cache ({:.1}% hit rate)",
+                    self.answers.hits(),
+                    lookups,
+                    self.answers.hit_rate()
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// The queries the raw engine sent again and the ones it gave up on, if it was used.
+    fn engine_counts(&self) -> (usize, usize) {
+        self.raw
+            .get()
+            .map_or((0, 0), |engine| (engine.retries(), engine.timeouts()))
+    }
+
+    /// The metrics served on the metrics address.
+    pub(crate) async fn metrics(&self) -> String {
+        self.stats.prometheus(self.engine_counts()).await
+    }
+
+    /// Starts serving the metrics, if there's an address to serve them on.
+    async fn serve_metrics(resolver: &Arc<Self>) -> Result<()> {
+        if let Some(addr) = resolver.metrics_addr {
+            let listener = tokio::net::TcpListener::bind(addr).await?;
+            info!("serving metrics on http://{}/metrics", addr);
+            tokio::spawn(metrics::serve(listener, Arc::clone(resolver)));
+        }
+        Ok(())
+    }
+
+    /// Writes the stats of the run next to the output file, e.g `results.stats.json` for
+    /// `results.json`.
+    async fn write_stats(&self) -> Result<()> {
+        let report = self.stats.report(self.engine_counts()).await;
+
+        let path = self.output_path.with_extension("stats.json");
+        fs::write(&path, serde_json::to_vec_pretty(&report)?).await?;
+        println!("Stats written to {:?}", path);
+        Ok(())
+    }
+
+    /// The resolve method is responsible for enumerating all provided nameservers for all hosts.
+    /// Currently it does parallel Ipv4 & Ipv6 lookups for A and AAAA records and all of their
+    /// intermediate records. These records will then be cached before later being serialized into
+    /// either json or csv format.
+    pub async fn resolve<I>(self, hosts: I, concurrency: usize) -> Result<()>
+    where
+        I: IntoIterator<Item = String>,
+    {
+        let cache = ResultsCache::new();
+        let mut resolver = self;
+        resolver.shutdown = Shutdown::listen();
+        resolver.start_engine()?;
+        resolver.open_state(&cache).await?;
+        let resolver = Arc::new(resolver);
+        Self::serve_metrics(&resolver).await?;
+
+        Self::run(&resolver, hosts, concurrency, &cache).await?;
+        Self::process(&resolver, concurrency, &cache).await?;
+        resolver.write(&cache).await
+    }
+
+    /// Brute forces subdomains of the wordlist's domain. The candidates are generated lazily and
+    /// go through the same pipeline as `resolve`, but the domain's wildcard answers are looked up
+    /// before starting so any candidate that only resolved because of the wildcard is discarded.
+    /// Candidates that failed to resolve aren't findings, so errors are discarded as well.
+    pub async fn brute(self, words: Wordlist, concurrency: usize) -> Result<()> {
+        let cache = ResultsCache::new();
+        let mut resolver = self;
+        resolver.shutdown = Shutdown::listen();
+        resolver.start_engine()?;
+        resolver.open_state(&cache).await?;
+        let resolver = Arc::new(resolver);
+        Self::serve_metrics(&resolver).await?;
+
+        let pool = match resolver.wildcard_mode {
+            WildcardMode::Keep => HashSet::new(),
+            _ => {
+                resolver
+                    .wildcard_pool(&resolver.wildcard_resolver(), words.domain())
+                    .await
+            }
+        };
+        if !pool.is_empty() {
+            info!("{} has a wildcard, discarding {:?}", words.domain(), pool);
+        }
+
+        Self::run(&resolver, words, concurrency, &cache).await?;
+        // The errors are needed to find dangling CNAMEs, so this has to happen before discarding.
+        Self::process(&resolver, concurrency, &cache).await?;
+        // Like `detect_wildcard`, a candidate is only discarded when all of its answers came from
+        // the wildcard, so a real host that shares an address with it keeps every record.
+        let wildcards: HashSet<String> = cache
+            .answers()
+            .await
+            .into_iter()
+            .filter(|(_, values)| values.is_subset(&pool))
+            .map(|(query, _)| query)
+            .collect();
+        cache
+            .retain(|(query, _, _, _, _), record| match record {
+                ResolveResponse::Error { .. } => false,
+                _ => !wildcards.contains(query),
+            })
+            .await;
+        resolver.write(&cache).await
+    }
+
+    /// Opens the checkpoint in the state directory, if there is one. When resuming, the responses
+    /// saved by the earlier run are put back into `cache`.
+    async fn open_state(&mut self, cache: &ResultsCache) -> Result<()> {
+        let dir = match &self.state_dir {
+            Some(dir) => dir,
+            None => return Ok(()),
+        };
+        let (checkpoint, mut responses) = Checkpoint::open(dir, self.resume)?;
+        if self.resume {
+            println!(
+                "Resuming from {:?}, skipping {} completed hosts",
+                dir,
+                checkpoint.num_completed()
+            );
+        }
+        cache.insert(&mut responses).await;
+        self.checkpoint = Some(Arc::new(checkpoint));
+        Ok(())
+    }
+
+    /// Everything that happens to the results between resolving the hosts and writing them out.
+    async fn process(
+        resolver: &Arc<Self>,
+        concurrency: usize,
+        cache: &Arc<ResultsCache>,
+    ) -> Result<()> {
+        if let Some(permutator) = &resolver.permutator {
+            Self::permutation_rounds(resolver, permutator, concurrency, cache).await?;
+        }
+        resolver.cloud_ranges.tag(cache).await;
+        if let Some(signal) = resolver.shutdown.requested() {
+            // The rest would take another round of lookups for every host. Wildcards are still
+            // flagged if it doesn't take too long, without them the results could be mostly noise.
+            let wildcards = resolver.wildcards(cache, concurrency);
+            if tokio::time::timeout(DRAIN_TIMEOUT, wildcards)
+                .await
+                .is_err()
+            {
+                warn!(
+                    "skipped detecting the wildcards, it took too long after {}",
+                    signal
+                );
+            }
+            let mut incomplete = VecDeque::new();
+            incomplete.push_back(ResolveResponse::Incomplete {
+                incomplete: true,
+                reason: format!("interrupted by {}", signal),
+            });
+            cache.insert(&mut incomplete).await;
+            return Ok(());
+        }
+        resolver.wildcards(cache, concurrency).await;
+        if resolver.dnssec {
+            dnssec::validate(&resolver.config, resolver.options, cache, concurrency).await;
+        }
+        if let Some(fingerprints) = &resolver.fingerprints {
+            fingerprints.check(cache).await;
         }
         Ok(())
     }
+
+    /// Attempts a zone transfer of `zone` from each of the `nameservers`, or from each of its
+    /// authoritative nameservers if none were given.
+    async fn transfer_zone(
+        &self,
+        resolver: &Client,
+        zone: String,
+        nameservers: &[SocketAddr],
+    ) -> VecDeque<ResolveResponse> {
+        let servers = match authority::zone_servers(resolver, &zone, nameservers).await {
+            Ok(servers) => servers,
+            Err(e) => return ResolveResponse::from_error(e).into_iter().collect(),
+        };
+
+        let query = Arc::new(format!("{}.", zone.trim_end_matches('.')));
+        let attempts = servers
+            .into_iter()
+            .map(|server| axfr::attempt(server, Arc::clone(&query), self.options.timeout));
+        futures::future::join_all(attempts)
+            .await
+            .into_iter()
+            .flatten()
+            .collect()
+    }
+
+    /// Attempts a zone transfer of every domain from each of its authoritative nameservers. The
+    /// nameservers are looked up using the resolvers, unless `nameservers` is given, in which case
+    /// the transfers are only attempted against those. Any transferred records and the errors of
+    /// the failed attempts are written out like the results of `resolve`.
+    pub async fn axfr(
+        self,
+        domains: Vec<String>,
+        nameservers: Vec<SocketAddr>,
+        concurrency: usize,
+    ) -> Result<()> {
+        let cache = ResultsCache::new();
+        let resolver = Client::new(self.config.clone(), self.options);
+
+        let transfers = futures::stream::iter(domains)
+            .map(|domain| self.transfer_zone(&resolver, domain, &nameservers))
+            .buffer_unordered(concurrency)
+            .collect::<Vec<_>>()
+            .await;
+
+        for mut records in transfers {
+            cache.insert(&mut records).await;
+        }
+        self.cloud_ranges.tag(&cache).await;
+        self.write(&cache).await
+    }
+
+    /// Walks `zone` using each of the `nameservers` in turn, or each of its authoritative
+    /// nameservers if none were given, until one of them lets us.
+    async fn walk_zone(
+        &self,
+        resolver: &Client,
+        zone: String,
+        nameservers: &[SocketAddr],
+    ) -> (String, Result<Walk>) {
+        let servers = match authority::zone_servers(resolver, &zone, nameservers).await {
+            Ok(servers) => servers,
+            Err(e) => return (zone, Err(ReeError::Walk(e.to_string()))),
+        };
+
+        let mut result = Err(ReeError::Walk(format!("no nameservers found for {}", zone)));
+        for server in servers {
+            result = walk::walk(server, &zone, self.options.timeout).await;
+            match &result {
+                Ok(_) => break,
+                Err(e) => warn!("walk of {} using {} failed: {}", zone, server, e),
+            }
+        }
+        (zone, result)
+    }
+
+    /// Enumerates the names of every domain by following its NSEC chain, and resolves them like
+    /// `resolve` would. Zones using NSEC3 only give away the hashes of their names, so those are
+    /// written to `hashes` to be cracked offline instead. The nameservers are picked the same way
+    /// as they are for `axfr`.
+    pub async fn walk(
+        mut self,
+        domains: Vec<String>,
+        nameservers: Vec<SocketAddr>,
+        hashes: PathBuf,
+        concurrency: usize,
+    ) -> Result<()> {
+        let cache = ResultsCache::new();
+        self.shutdown = Shutdown::listen();
+        self.start_engine()?;
+        let lookup = Client::new(self.config.clone(), self.options);
+
+        let walks = futures::stream::iter(domains)
+            .map(|domain| self.walk_zone(&lookup, domain, &nameservers))
+            .buffer_unordered(concurrency)
+            .collect::<Vec<_>>()
+            .await;
+
+        let mut names = Vec::new();
+        let mut found = Vec::new();
+        for (zone, result) in walks {
+            match result {
+                Ok(walk) => {
+                    names.extend(walk.names);
+                    found.extend(walk.hashes);
+                }
+                Err(e) => {
+                    let mut errors = VecDeque::new();
+                    errors.push_back(ResolveResponse::Error {
+                        query: format!("{}.", zone.trim_end_matches('.')),
+                        response_code: e.to_string(),
+                        authority: None,
+                        subnet: None,
+                    });
+                    cache.insert(&mut errors).await;
+                }
+            }
+        }
+
+        if !found.is_empty() {
+            fs::write(&hashes, found.join("\n") + "\n").await?;
+            println!("{} NSEC3 hashes written to {:?}", found.len(), hashes);
+        }
+
+        self.open_state(&cache).await?;
+        let resolver = Arc::new(self);
+        Self::serve_metrics(&resolver).await?;
+        Self::run(&resolver, names, concurrency, &cache).await?;
+        Self::process(&resolver, concurrency, &cache).await?;
+        resolver.write(&cache).await
+    }
+
+    /// Detects the wildcard hosts and then keeps, drops or only keeps their records depending on
+    /// the `WildcardMode`.
+    async fn wildcards(&self, cache: &Arc<ResultsCache>, concurrency: usize) {
+        let keep_wildcards = match self.wildcard_mode {
+            WildcardMode::Keep => return,
+            WildcardMode::Flag => {
+                self.detect_wildcard(Arc::clone(cache), concurrency).await;
+                return;
+            }
+            WildcardMode::Drop => false,
+            WildcardMode::Only => true,
+        };
+
+        let wildcards = self.detect_wildcard(Arc::clone(cache), concurrency).await;
+        cache
+            .retain(|_, record| match record {
+                ResolveResponse::IpRecord { query, .. }
+                | ResolveResponse::Record { query, .. }
+                | ResolveResponse::Chain { query, .. } => {
+                    wildcards.contains_key(query) == keep_wildcards
+                }
+                // Errors and takeovers don't belong to an answer, so they're only kept when the
+                // wildcards are dropped.
+                _ => !keep_wildcards,
+            })
+            .await;
+    }
+}
+
+/// Whether the lookup showed that the name it looked up doesn't exist.
+fn is_nxdomain<T>(lookup: &std::result::Result<T, ResolveError>) -> bool {
+    match lookup {
+        Err(e) => matches!(
+            e.kind(),
+            ResolveErrorKind::NoRecordsFound {
+                response_code: ResponseCode::NXDomain,
+                ..
+            }
+        ),
+        Ok(_) => false,
+    }
 }
 
-// temporary function used to convert the `ResolveError` and push it into the channel, which will
-// then later be stored in the `ResultsCache`. Made a function to avoid the duplication inside the
-// `resolver.deliver_response` method.
-async fn push_error(
-    error: ResolveError,
-    mut sender: Sender<VecDeque<ResolveResponse>>,
-) -> Result<()> {
+/// Converts the records of a lookup into our `ResolveResponse` type, or the error it failed with.
+fn lookup_responses(
+    lookup: std::result::Result<Lookup, ResolveError>,
+) -> VecDeque<ResolveResponse> {
+    match lookup {
+        Ok(lookup) => responses(&lookup).0,
+        Err(e) => error_responses(e),
+    }
+}
+
+/// Like `lookup_responses`, with the CNAME chains that led to the addresses as well.
+fn lookup_ip_responses(
+    lookup: std::result::Result<LookupIp, ResolveError>,
+) -> VecDeque<ResolveResponse> {
+    match lookup {
+        Ok(lookup) => {
+            let (mut records, query) = responses(lookup.as_lookup());
+            records.extend(ResolveResponse::chains(
+                lookup.as_lookup().record_iter(),
+                &query,
+            ));
+            records
+        }
+        Err(e) => error_responses(e),
+    }
+}
+
+/// The records of `lookup`, along with the name that was looked up.
+fn responses(lookup: &Lookup) -> (VecDeque<ResolveResponse>, Arc<String>) {
+    let query = Arc::new(lookup.query().name().to_utf8());
+    let records = lookup
+        .record_iter()
+        .map(|record| {
+            info!("got {:?}", record);
+            ResolveResponse::new(record, Arc::clone(&query))
+        })
+        .collect();
+    (records, query)
+}
+
+/// Converts the `ResolveError` into the response that is stored in the `ResultsCache`, if it's
+/// one that is kept at all.
+fn error_responses(error: ResolveError) -> VecDeque<ResolveResponse> {
     warn!("got error {:?}", error);
+    ResolveResponse::from_error(error).into_iter().collect()
+}
+
+/// Returns the parent zones of `name` from the closest to the furthest, without the top level
+/// domain, e.g `api.dev.example.com.` returns `dev.example.com` and `example.com`.
+fn parent_zones(name: &str) -> impl Iterator<Item = &str> {
+    let name = name.trim_end_matches('.');
+    name.match_indices('.')
+        .map(move |(i, _)| &name[i + 1..])
+        .filter(|zone| zone.contains('.'))
+}
+
+/// Generates a random label that is used as a canary for the wildcard lookups. These are random
+/// for every run, so they can't be fingerprinted and special cased by a nameserver.
+pub(crate) fn canary() -> String {
+    use rand::distributions::Alphanumeric;
+    use rand::Rng;
+
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(16)
+        .map(|c| char::from(c).to_ascii_lowercase())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stub;
+    use trust_dns_proto::rr::Name;
+
+    fn errors(names: &[&str]) -> Output {
+        let errors = names
+            .iter()
+            .map(|name| ResolveResponse::Error {
+                query: format!("{}.", name),
+                response_code: "NXDomain".to_owned(),
+                authority: None,
+                subnet: None,
+            })
+            .collect();
+        Output::Responses(errors)
+    }
+
+    /// Runs `test` with the clock paused, in a task of its own. The runtime parks whenever the
+    /// test's own future is waiting, which moves a paused clock on to the next timer, but never
+    /// while a task is ready, so the clock only moves when the test advances it.
+    async fn paused<F>(test: F)
+    where
+        F: std::future::Future<Output = ()> + Send + 'static,
+    {
+        tokio::time::pause();
+        tokio::spawn(test).await.unwrap();
+    }
+
+    /// Lets the spawned `cache_responses` take everything it can from the channel, and the timers
+    /// that are due fire.
+    async fn settle() {
+        for _ in 0..128 {
+            let _ = tokio::task::yield_now().await;
+        }
+    }
+
+    /// Starts `cache_responses`, and lets the first tick of its interval go by. It's due straight
+    /// away, but timers only fire once the millisecond they're due in has passed.
+    async fn start(
+        resolver: Resolver,
+    ) -> (
+        Sender<Output>,
+        Arc<ResultsCache>,
+        tokio::task::JoinHandle<()>,
+    ) {
+        let (sender, receiver) = channel(CHAN_SIZE);
+        let cache = ResultsCache::new();
+        let handle = tokio::spawn(Resolver::cache_responses(
+            receiver,
+            Arc::clone(&cache),
+            Arc::new(resolver),
+        ));
+        settle().await;
+        tokio::time::advance(Duration::from_millis(1)).await;
+        settle().await;
+        (sender, cache, handle)
+    }
+
+    #[tokio::test]
+    async fn messages_stepping_past_the_batch_size_are_cached() {
+        paused(async {
+            let resolver = Resolver::default()
+                .batch_size(4)
+                .flush_interval(Duration::from_secs(3600));
+            let (mut sender, cache, handle) = start(resolver).await;
+
+            sender.send(errors(&["a", "b", "c"])).await.unwrap();
+            settle().await;
+            assert_eq!(cache.num_results().await, 0);
+
+            // The queue goes from 3 to 6 responses, past the batch size without ever being at it.
+            sender.send(errors(&["d", "e", "f"])).await.unwrap();
+            settle().await;
+            assert_eq!(cache.num_results().await, 6);
+
+            sender.send(errors(&["g"])).await.unwrap();
+            settle().await;
+            assert_eq!(cache.num_results().await, 6);
+
+            // Whatever is left in the queue is cached once the channel closes.
+            drop(sender);
+            handle.await.unwrap();
+            assert_eq!(cache.num_results().await, 7);
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn the_queue_is_flushed_every_interval() {
+        paused(async {
+            let resolver = Resolver::default()
+                .batch_size(100)
+                .flush_interval(Duration::from_secs(10));
+            let (mut sender, cache, _handle) = start(resolver).await;
+
+            sender.send(errors(&["a", "b"])).await.unwrap();
+            settle().await;
+            assert_eq!(cache.num_results().await, 0);
+
+            tokio::time::advance(Duration::from_secs(9)).await;
+            settle().await;
+            assert_eq!(cache.num_results().await, 0);
+
+            tokio::time::advance(Duration::from_secs(1)).await;
+            settle().await;
+            assert_eq!(cache.num_results().await, 2);
+
+            sender.send(errors(&["c"])).await.unwrap();
+            tokio::time::advance(Duration::from_secs(10)).await;
+            settle().await;
+            assert_eq!(cache.num_results().await, 3);
+        })
+        .await;
+    }
+
+    fn state_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("ree-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[tokio::test]
+    async fn only_finished_hosts_are_completed_once_their_responses_are_saved() {
+        let dir = state_dir("finished");
+        let (checkpoint, _) = Checkpoint::open(&dir, false).unwrap();
+        let resolver = Resolver {
+            checkpoint: Some(Arc::new(checkpoint)),
+            ..Resolver::default()
+        };
+        let (mut sender, receiver) = channel(CHAN_SIZE);
+        let handle = tokio::spawn(Resolver::cache_responses(
+            receiver,
+            ResultsCache::new(),
+            Arc::new(resolver),
+        ));
+
+        sender.send(errors(&["a"])).await.unwrap();
+        sender.send(Output::Finished("a".to_owned())).await.unwrap();
+        // Still being looked up when the run stops.
+        sender.send(errors(&["b"])).await.unwrap();
+        drop(sender);
+        handle.await.unwrap();
+
+        let (resumed, responses) = Checkpoint::open(&dir, true).unwrap();
+        assert!(resumed.is_completed("a"));
+        assert!(!resumed.is_completed("b"));
+        assert_eq!(responses.len(), 2);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 
-    let mut errors: VecDeque<ResolveResponse> = VecDeque::new();
-    let error_response = ResolveResponse::from_error(error);
+    #[tokio::test]
+    async fn resuming_skips_the_completed_hosts() {
+        let dir = state_dir("resume");
+        let (checkpoint, _) = Checkpoint::open(&dir, false).unwrap();
+        checkpoint
+            .complete(&["done.example.test".to_owned()])
+            .await
+            .unwrap();
 
-    if let Some(e) = error_response {
-        errors.push_front(e);
-        sender.send(errors).await?;
+        let asked = Arc::new(std::sync::Mutex::new(HashSet::new()));
+        let seen = Arc::clone(&asked);
+        let server = stub::nameserver("example.test.", move |query: &Name, _| {
+            seen.lock().unwrap().insert(query.to_utf8());
+            (ResponseCode::NXDomain, vec![])
+        });
+        let mut resolver = Resolver::default()
+            .nameservers(vec![server])
+            .state_dir(dir.clone())
+            .resume();
+        let cache = ResultsCache::new();
+        resolver.open_state(&cache).await.unwrap();
+        let hosts = vec![
+            "done.example.test".to_owned(),
+            "new.example.test".to_owned(),
+        ];
+        Resolver::run(&Arc::new(resolver), hosts, 4, &cache)
+            .await
+            .unwrap();
+
+        let asked = asked.lock().unwrap();
+        assert!(asked.contains("new.example.test."));
+        assert!(!asked.contains("done.example.test."));
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 
-    Ok(())
+    /// A nameserver with wildcards at `*.wild.example.test` and `*.dev.example.test`, and no
+    /// other names. The wildcards only answer for the names one label under them, as if the names
+    /// between them and deeper names existed.
+    fn wildcard_nameserver() -> SocketAddr {
+        stub::nameserver("example.test.", |query: &Name, kind| {
+            let host = query.to_utf8();
+            let parent = host.split_once('.').map_or("", |(_, parent)| parent);
+            let ip = match parent {
+                "wild.example.test." => [192, 0, 2, 99],
+                "dev.example.test." => [192, 0, 2, 98],
+                _ => return (ResponseCode::NXDomain, vec![]),
+            };
+            match kind {
+                RecordType::A => (ResponseCode::NoError, vec![stub::a(query, ip)]),
+                _ => (ResponseCode::NoError, vec![]),
+            }
+        })
+    }
+
+    fn addresses(hosts: &[(&str, [u8; 4])]) -> VecDeque<ResolveResponse> {
+        hosts
+            .iter()
+            .map(|(host, ip)| {
+                ResolveResponse::new(&stub::a(&stub::name(host), *ip), Arc::new(host.to_string()))
+            })
+            .collect()
+    }
+
+    async fn cache_of(mut responses: VecDeque<ResolveResponse>) -> Arc<ResultsCache> {
+        let cache = ResultsCache::new();
+        cache.insert(&mut responses).await;
+        cache
+    }
+
+    fn resolver(mode: &str) -> Resolver {
+        Resolver::default()
+            .nameservers(vec![wildcard_nameserver()])
+            .wildcard(mode)
+    }
+
+    #[test]
+    fn socket_options_need_the_raw_engine() {
+        let resolvers = [
+            Resolver::default().bind_address(IpAddr::from([127, 0, 0, 1])),
+            Resolver::default().source_ports(40000..=40999),
+            Resolver::default().sockets(4),
+        ];
+        for resolver in resolvers {
+            assert!(matches!(resolver.start_engine(), Err(ReeError::Engine(_))));
+        }
+        assert!(Resolver::default().start_engine().is_ok());
+    }
+
+    #[tokio::test]
+    async fn canaries_find_the_pool_of_each_wildcard() {
+        let resolver = resolver("flag");
+        let pool = resolver
+            .wildcard_pool(&resolver.wildcard_resolver(), "wild.example.test")
+            .await;
+        assert_eq!(pool, HashSet::from(["192.0.2.99".to_owned()]));
+        let pool = resolver
+            .wildcard_pool(&resolver.wildcard_resolver(), "example.test")
+            .await;
+        assert!(pool.is_empty());
+    }
+
+    #[tokio::test]
+    async fn only_hosts_whose_answers_are_all_in_a_pool_are_wildcards() {
+        let cache = cache_of(addresses(&[
+            ("a.wild.example.test.", [192, 0, 2, 99]),
+            // A real answer that differs from the wildcard's.
+            ("real.wild.example.test.", [192, 0, 2, 5]),
+            ("mixed.wild.example.test.", [192, 0, 2, 99]),
+            ("mixed.wild.example.test.", [192, 0, 2, 5]),
+            // The closest parent with a wildcard is the one that's checked.
+            ("x.api.dev.example.test.", [192, 0, 2, 98]),
+            ("y.api.dev.example.test.", [192, 0, 2, 99]),
+            ("www.example.test.", [192, 0, 2, 1]),
+        ]))
+        .await;
+
+        let wildcards = resolver("flag").detect_wildcard(cache, 8).await;
+        assert_eq!(
+            wildcards,
+            HashMap::from([
+                (
+                    "a.wild.example.test.".to_owned(),
+                    "wild.example.test".to_owned()
+                ),
+                (
+                    "x.api.dev.example.test.".to_owned(),
+                    "dev.example.test".to_owned()
+                ),
+            ])
+        );
+    }
+
+    /// The queries of the rows left in the cache after filtering the wildcards with `mode`, and
+    /// whether they're flagged as wildcards.
+    async fn filtered(mode: &str) -> Vec<(String, bool)> {
+        let mut responses = addresses(&[
+            ("a.wild.example.test.", [192, 0, 2, 99]),
+            ("www.example.test.", [192, 0, 2, 1]),
+        ]);
+        responses.push_back(ResolveResponse::Chain {
+            query: "a.wild.example.test.".to_owned(),
+            chain: vec!["a.wild.example.test.".to_owned(), "192.0.2.99".to_owned()],
+        });
+        responses.push_back(ResolveResponse::Error {
+            query: "gone.example.test.".to_owned(),
+            response_code: "Non-Existent Domain".to_owned(),
+            authority: None,
+            subnet: None,
+        });
+        responses.push_back(ResolveResponse::Zone {
+            zone: "example.test".to_owned(),
+            signed: false,
+        });
+        responses.push_back(ResolveResponse::Takeover {
+            query: "old.example.test.".to_owned(),
+            target: "old.example.net.".to_owned(),
+            service: None,
+        });
+        let cache = cache_of(responses).await;
+
+        resolver(mode).wildcards(&cache, 8).await;
+        let mut rows: Vec<(String, bool)> = cache
+            .inner
+            .lock()
+            .await
+            .values()
+            .map(|row| match row {
+                ResolveResponse::IpRecord {
+                    query, is_wildcard, ..
+                } => (query.clone(), *is_wildcard),
+                ResolveResponse::Chain { query, .. } => (format!("chain {}", query), false),
+                ResolveResponse::Error { query, .. } => (format!("error {}", query), false),
+                ResolveResponse::Zone { zone, .. } => (format!("zone {}", zone), false),
+                ResolveResponse::Takeover { query, .. } => (format!("takeover {}", query), false),
+                other => panic!("unexpected row {:?}", other),
+            })
+            .collect();
+        rows.sort();
+        rows
+    }
+
+    fn rows(rows: &[(&str, bool)]) -> Vec<(String, bool)> {
+        rows.iter().map(|(q, w)| (q.to_string(), *w)).collect()
+    }
+
+    #[tokio::test]
+    async fn keep_skips_the_detection() {
+        assert_eq!(
+            filtered("keep").await,
+            rows(&[
+                ("a.wild.example.test.", false),
+                ("chain a.wild.example.test.", false),
+                ("error gone.example.test.", false),
+                ("takeover old.example.test.", false),
+                ("www.example.test.", false),
+                ("zone example.test", false),
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn flag_marks_the_wildcards() {
+        assert_eq!(
+            filtered("flag").await,
+            rows(&[
+                ("a.wild.example.test.", true),
+                ("chain a.wild.example.test.", false),
+                ("error gone.example.test.", false),
+                ("takeover old.example.test.", false),
+                ("www.example.test.", false),
+                ("zone example.test", false),
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn drop_discards_the_wildcards() {
+        assert_eq!(
+            filtered("drop").await,
+            rows(&[
+                ("error gone.example.test.", false),
+                ("takeover old.example.test.", false),
+                ("www.example.test.", false),
+                ("zone example.test", false),
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn only_keeps_just_the_wildcards() {
+        assert_eq!(
+            filtered("only").await,
+            rows(&[
+                ("a.wild.example.test.", true),
+                ("chain a.wild.example.test.", false),
+            ])
+        );
+    }
 }
@@ -0,0 +1,126 @@
+use std::time::Duration;
+use tokio::sync::watch;
+
+/// How long the lookups in flight get to finish once a shutdown was requested, and how long the
+/// wildcard detection gets afterwards, before they're given up on.
+pub(crate) const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Listens for SIGINT and SIGTERM, so an interrupted run stops taking new hosts and still writes
+/// out what it found. A second signal exits straight away. The default never requests a shutdown.
+#[derive(Debug, Clone)]
+pub(crate) struct Shutdown {
+    receiver: watch::Receiver<Option<&'static str>>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        let (_, receiver) = watch::channel(None);
+        Self { receiver }
+    }
+}
+
+impl Shutdown {
+    /// Starts listening for the signals, this has to be called from inside the runtime.
+    pub(crate) fn listen() -> Self {
+        let (sender, receiver) = watch::channel(None);
+        tokio::spawn(async move {
+            let signal = next_signal().await;
+            eprintln!(
+                "Received {}, writing the results found so far. Press Ctrl-C again to exit now.",
+                signal
+            );
+            let _ = sender.broadcast(Some(signal));
+            next_signal().await;
+            std::process::exit(130);
+        });
+        Self { receiver }
+    }
+
+    /// The signal that requested the shutdown, if there was one.
+    pub(crate) fn requested(&self) -> Option<&'static str> {
+        *self.receiver.borrow()
+    }
+
+    /// Waits until the lookups in flight have had `DRAIN_TIMEOUT` to finish since a shutdown was
+    /// requested, which is never if nobody requests one.
+    pub(crate) async fn drained(&self) {
+        let mut receiver = self.receiver.clone();
+        while receiver.borrow().is_none() {
+            // The sender is only dropped when nobody is listening for the signals.
+            if receiver.recv().await.is_none() {
+                futures::future::pending::<()>().await;
+            }
+        }
+        tokio::time::delay_for(DRAIN_TIMEOUT).await;
+    }
+}
+
+#[cfg(unix)]
+async fn next_signal() -> &'static str {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut terminate = signal(SignalKind::terminate()).expect("error listening for SIGTERM");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => "SIGINT",
+        _ = terminate.recv() => "SIGTERM",
+    }
+}
+
+#[cfg(not(unix))]
+async fn next_signal() -> &'static str {
+    let _ = tokio::signal::ctrl_c().await;
+    "SIGINT"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    /// Lets the spawned tasks run until they're all waiting, and the timers that are due fire.
+    async fn settle() {
+        for _ in 0..32 {
+            let _ = tokio::task::yield_now().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn lookups_are_drained_a_timeout_after_the_request() {
+        // The test runs in a task of its own, since the paused clock moves on to the next timer
+        // whenever the runtime parks on the test's own future.
+        tokio::time::pause();
+        let test = tokio::spawn(async {
+            let (sender, receiver) = watch::channel(None);
+            let shutdown = Shutdown { receiver };
+            let drained = Arc::new(AtomicBool::new(false));
+            let (waiting, done) = (shutdown.clone(), Arc::clone(&drained));
+            tokio::spawn(async move {
+                waiting.drained().await;
+                done.store(true, Ordering::SeqCst);
+            });
+
+            tokio::time::advance(Duration::from_secs(3600)).await;
+            settle().await;
+            assert_eq!(shutdown.requested(), None);
+            assert!(!drained.load(Ordering::SeqCst));
+
+            sender.broadcast(Some("SIGTERM")).unwrap();
+            settle().await;
+            assert_eq!(shutdown.requested(), Some("SIGTERM"));
+            tokio::time::advance(DRAIN_TIMEOUT - Duration::from_millis(1)).await;
+            settle().await;
+            assert!(!drained.load(Ordering::SeqCst));
+
+            // Timers fire once the millisecond they're due in has passed.
+            tokio::time::advance(Duration::from_millis(2)).await;
+            settle().await;
+            assert!(drained.load(Ordering::SeqCst));
+        });
+        test.await.unwrap();
+    }
+
+    #[test]
+    fn the_default_never_requests_a_shutdown() {
+        assert_eq!(Shutdown::default().requested(), None);
+    }
+}
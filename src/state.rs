@@ -0,0 +1,183 @@
+use crate::data::ResolveResponse;
+use crate::Result;
+use std::collections::{HashSet, VecDeque};
+use std::fs::{self, OpenOptions};
+use std::path::Path;
+use tokio::fs::File;
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::sync::Mutex;
+use tracing::warn;
+
+/// The hosts that were completed, one per line.
+const COMPLETED: &str = "completed.txt";
+/// Every response received so far, one json object per line.
+const RESULTS: &str = "results.jsonl";
+
+/// The files a run is checkpointed to, so a run that was killed can pick up where it stopped
+/// instead of starting over. Responses are appended as they arrive, and a host is only written to
+/// the completed hosts after its responses were flushed, so a completed host never loses any.
+#[derive(Debug)]
+pub(crate) struct Checkpoint {
+    // The hosts completed by the run being resumed.
+    completed: HashSet<String>,
+    files: Mutex<Files>,
+}
+
+#[derive(Debug)]
+struct Files {
+    completed: BufWriter<File>,
+    results: BufWriter<File>,
+}
+
+impl Checkpoint {
+    /// Opens the checkpoint files in `dir`. When resuming, the hosts that were completed are
+    /// loaded and the responses saved so far are returned, otherwise the files are started over.
+    pub(crate) fn open(dir: &Path, resume: bool) -> Result<(Self, VecDeque<ResolveResponse>)> {
+        fs::create_dir_all(dir)?;
+        let (completed, responses) = if resume {
+            (
+                load_completed(&dir.join(COMPLETED))?,
+                load_results(&dir.join(RESULTS))?,
+            )
+        } else {
+            (HashSet::new(), VecDeque::new())
+        };
+
+        let open = |name: &str| -> Result<BufWriter<File>> {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(resume)
+                .write(true)
+                .truncate(!resume)
+                .open(dir.join(name))?;
+            Ok(BufWriter::new(File::from_std(file)))
+        };
+        let files = Files {
+            completed: open(COMPLETED)?,
+            results: open(RESULTS)?,
+        };
+
+        Ok((
+            Self {
+                completed,
+                files: Mutex::new(files),
+            },
+            responses,
+        ))
+    }
+
+    /// Whether `host` was completed by the run being resumed.
+    pub(crate) fn is_completed(&self, host: &str) -> bool {
+        self.completed.contains(host)
+    }
+
+    /// The number of hosts completed by the run being resumed.
+    pub(crate) fn num_completed(&self) -> usize {
+        self.completed.len()
+    }
+
+    /// Appends `responses` to the saved responses. They're buffered, and only flushed once the
+    /// hosts they belong to are completed.
+    pub(crate) async fn save(&self, responses: &VecDeque<ResolveResponse>) -> Result<()> {
+        let mut lines = Vec::new();
+        for response in responses {
+            serde_json::to_writer(&mut lines, response)?;
+            lines.push(b'\n');
+        }
+        self.files.lock().await.results.write_all(&lines).await?;
+        Ok(())
+    }
+
+    /// Marks `hosts` as completed, after flushing every response saved so far.
+    pub(crate) async fn complete(&self, hosts: &[String]) -> Result<()> {
+        let mut files = self.files.lock().await;
+        files.results.flush().await?;
+        for host in hosts {
+            files
+                .completed
+                .write_all(format!("{}\n", host).as_bytes())
+                .await?;
+        }
+        files.completed.flush().await?;
+        Ok(())
+    }
+}
+
+fn load_completed(path: &Path) -> Result<HashSet<String>> {
+    if !path.exists() {
+        return Ok(HashSet::new());
+    }
+    Ok(fs::read_to_string(path)?
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(str::to_owned)
+        .collect())
+}
+
+fn load_results(path: &Path) -> Result<VecDeque<ResolveResponse>> {
+    if !path.exists() {
+        return Ok(VecDeque::new());
+    }
+    let mut responses = VecDeque::new();
+    for (number, line) in fs::read_to_string(path)?.lines().enumerate() {
+        // The run could have been killed halfway through writing the last line.
+        match serde_json::from_str(line) {
+            Ok(response) => responses.push_back(response),
+            Err(e) => warn!("skipping line {} of {:?}: {}", number + 1, path, e),
+        }
+    }
+    Ok(responses)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn error(query: &str) -> ResolveResponse {
+        ResolveResponse::Error {
+            query: query.to_owned(),
+            response_code: "Non-Existent Domain".to_owned(),
+            authority: None,
+            subnet: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn a_checkpoint_is_read_back_when_resuming() {
+        let dir = std::env::temp_dir().join(format!("ree-checkpoint-{}", std::process::id()));
+        let (checkpoint, responses) = Checkpoint::open(&dir, false).unwrap();
+        assert!(responses.is_empty());
+        checkpoint
+            .save(&VecDeque::from([error("a."), error("b.")]))
+            .await
+            .unwrap();
+        checkpoint.complete(&["a".to_owned()]).await.unwrap();
+        drop(checkpoint);
+
+        // A run killed halfway through writing a response.
+        let mut results = OpenOptions::new()
+            .append(true)
+            .open(dir.join(RESULTS))
+            .unwrap();
+        results.write_all(b"{\"query\":\"c.\",\"resp").unwrap();
+
+        let (resumed, responses) = Checkpoint::open(&dir, true).unwrap();
+        assert_eq!(resumed.num_completed(), 1);
+        assert!(resumed.is_completed("a"));
+        assert_eq!(
+            responses
+                .iter()
+                .map(ResolveResponse::key)
+                .collect::<Vec<_>>(),
+            vec![error("a.").key(), error("b.").key()]
+        );
+        drop(resumed);
+
+        // Starting over throws the checkpoint away.
+        let (fresh, responses) = Checkpoint::open(&dir, false).unwrap();
+        assert_eq!(fresh.num_completed(), 0);
+        assert!(responses.is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
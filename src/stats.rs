@@ -0,0 +1,460 @@
+use crate::data::ResolveResponse;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fmt::Write;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use trust_dns_proto::op::ResponseCode;
+
+/// The number of buckets in a `Histogram`: 1ms wide up to 100ms, 10ms wide up to 1s, 100ms wide
+/// up to 10s and one for everything slower.
+const BUCKETS: usize = 281;
+
+/// The bounds of the latency buckets exposed to Prometheus in milliseconds. Each of them is also
+/// the bound of one of the `Histogram`'s buckets, so the counts are exact.
+const PROMETHEUS_BUCKETS: [u64; 13] = [1, 2, 5, 10, 25, 50, 100, 250, 500, 1000, 2500, 5000, 10000];
+
+/// Latencies bucketed with a precision of about 10%, so they take the same space no matter how
+/// many queries were sent.
+#[derive(Debug, Clone)]
+pub(crate) struct Histogram {
+    counts: Vec<u64>,
+    count: u64,
+    sum: Duration,
+    max: Duration,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            counts: vec![0; BUCKETS],
+            count: 0,
+            sum: Duration::default(),
+            max: Duration::default(),
+        }
+    }
+}
+
+impl Histogram {
+    pub(crate) fn record(&mut self, latency: Duration) {
+        // Rounded up, so every latency is at or below the bound of its bucket.
+        let ms = (latency.as_micros() as u64).div_ceil(1000);
+        let bucket = match ms {
+            0..=100 => ms.saturating_sub(1),
+            101..=1000 => 100 + (ms - 101) / 10,
+            1001..=10000 => 190 + (ms - 1001) / 100,
+            _ => BUCKETS as u64 - 1,
+        };
+        self.counts[bucket as usize] += 1;
+        self.count += 1;
+        self.sum += latency;
+        self.max = self.max.max(latency);
+    }
+
+    pub(crate) fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub(crate) fn mean(&self) -> Duration {
+        Duration::from_secs_f64(self.sum.as_secs_f64() / self.count.max(1) as f64)
+    }
+
+    /// The upper bound of every bucket in milliseconds, with the number of latencies at or below
+    /// it. The last bucket has no upper bound.
+    pub(crate) fn cumulative(&self) -> impl Iterator<Item = (Option<u64>, u64)> + '_ {
+        self.counts
+            .iter()
+            .enumerate()
+            .scan(0, |total, (bucket, count)| {
+                *total += count;
+                Some((upper_bound(bucket), *total))
+            })
+    }
+
+    /// The latency that `percentile` percent of the latencies are at or below, rounded up to the
+    /// upper bound of its bucket.
+    pub(crate) fn percentile(&self, percentile: f64) -> Duration {
+        let rank = ((percentile / 100.0 * self.count as f64).ceil() as u64).max(1);
+        self.cumulative()
+            .find(|(_, total)| *total >= rank)
+            .and_then(|(bound, _)| bound)
+            .map_or(self.max, |ms| Duration::from_millis(ms).min(self.max))
+    }
+}
+
+/// The upper bound of `bucket` in milliseconds, or `None` for the last one.
+fn upper_bound(bucket: usize) -> Option<u64> {
+    let bucket = bucket as u64;
+    match bucket {
+        0..=99 => Some(bucket + 1),
+        100..=189 => Some(110 + (bucket - 100) * 10),
+        190..=279 => Some(1100 + (bucket - 190) * 100),
+        _ => None,
+    }
+}
+
+/// The number of messages waiting in one of the channels of the pipeline.
+#[derive(Debug, Default)]
+pub(crate) struct Gauge(AtomicUsize);
+
+impl Gauge {
+    pub(crate) fn add(&self, count: usize) {
+        self.0.fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub(crate) fn sub(&self, count: usize) {
+        self.0.fetch_sub(count, Ordering::Relaxed);
+    }
+
+    pub(crate) fn get(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Totals collected by the stages of the pipeline over the whole run, written out as a report
+/// next to the results once it's done.
+#[derive(Debug)]
+pub(crate) struct Stats {
+    started: Instant,
+    hosts: AtomicUsize,
+    queries: AtomicUsize,
+    lookups: AtomicUsize,
+    errors: AtomicUsize,
+    timeouts: AtomicUsize,
+    wildcard_hosts: AtomicUsize,
+    wildcard_zones: AtomicUsize,
+    rcodes: Mutex<HashMap<String, usize>>,
+    latencies: Mutex<HashMap<IpAddr, Histogram>>,
+    pub(crate) records_queue: Gauge,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            hosts: AtomicUsize::default(),
+            queries: AtomicUsize::default(),
+            lookups: AtomicUsize::default(),
+            errors: AtomicUsize::default(),
+            timeouts: AtomicUsize::default(),
+            wildcard_hosts: AtomicUsize::default(),
+            wildcard_zones: AtomicUsize::default(),
+            rcodes: Mutex::default(),
+            latencies: Mutex::default(),
+            records_queue: Gauge::default(),
+        }
+    }
+}
+
+impl Stats {
+    /// Counts a host that was resolved, or skipped because it was resolved before.
+    pub(crate) fn host_done(&self) {
+        self.hosts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts `count` queries that were sent.
+    pub(crate) fn queries(&self, count: usize) {
+        self.queries.fetch_add(count, Ordering::Relaxed);
+    }
+
+    /// Counts `count` lookups that got no response at all.
+    pub(crate) fn timeouts(&self, count: usize) {
+        self.timeouts.fetch_add(count, Ordering::Relaxed);
+    }
+
+    /// Records how long `resolver` took to answer.
+    pub(crate) async fn latency(&self, resolver: IpAddr, latency: Duration) {
+        self.latencies
+            .lock()
+            .await
+            .entry(resolver)
+            .or_default()
+            .record(latency);
+    }
+
+    /// Counts the outcome of a lookup that made it through the pipeline, by the response code of
+    /// its error if it failed. A name without records of the type that was asked for comes back
+    /// as an error with `NoError`, but it was still answered, so it doesn't count as one.
+    pub(crate) async fn lookup(&self, responses: &VecDeque<ResolveResponse>) {
+        // Lookups that timed out have nothing to count, they're counted as timeouts instead.
+        if responses.is_empty() {
+            return;
+        }
+        self.lookups.fetch_add(1, Ordering::Relaxed);
+        let no_error = ResponseCode::NoError.to_string();
+        let rcode = responses.iter().find_map(|response| match response {
+            ResolveResponse::Error { response_code, .. } if *response_code != no_error => {
+                Some(response_code)
+            }
+            _ => None,
+        });
+        if rcode.is_some() {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+        let rcode = rcode.unwrap_or(&no_error).clone();
+        *self.rcodes.lock().await.entry(rcode).or_default() += 1;
+    }
+
+    /// Records the wildcard hosts that were found, and the zones of their wildcards.
+    pub(crate) fn wildcards(&self, wildcards: &HashMap<String, String>) {
+        let zones: HashSet<&String> = wildcards.values().collect();
+        self.wildcard_hosts
+            .store(wildcards.len(), Ordering::Relaxed);
+        self.wildcard_zones.store(zones.len(), Ordering::Relaxed);
+    }
+
+    pub(crate) fn hosts(&self) -> usize {
+        self.hosts.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn sent(&self) -> usize {
+        self.queries.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn lookups(&self) -> usize {
+        self.lookups.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn errors(&self) -> usize {
+        self.errors.load(Ordering::Relaxed)
+    }
+
+    /// The number of lookups for each response code, sorted by the code.
+    pub(crate) async fn rcodes(&self) -> BTreeMap<String, usize> {
+        self.rcodes.lock().await.clone().into_iter().collect()
+    }
+
+    /// The report written out next to the results. Only the raw engine resends queries itself,
+    /// so the `retries` and the `timeouts` it gave up on come from the engine when it was used.
+    pub(crate) async fn report(&self, (retries, timeouts): (usize, usize)) -> Report {
+        let resolvers = self
+            .latencies
+            .lock()
+            .await
+            .iter()
+            .map(|(resolver, histogram)| {
+                let ms = |latency: Duration| latency.as_micros() as f64 / 1000.0;
+                let latency = Latency {
+                    answered: histogram.count(),
+                    mean_ms: ms(histogram.mean()),
+                    p50_ms: ms(histogram.percentile(50.0)),
+                    p90_ms: ms(histogram.percentile(90.0)),
+                    p99_ms: ms(histogram.percentile(99.0)),
+                    max_ms: ms(histogram.max),
+                };
+                (resolver.to_string(), latency)
+            })
+            .collect();
+
+        Report {
+            wall_time_secs: self.started.elapsed().as_secs_f64(),
+            hosts: self.hosts(),
+            queries: self.sent(),
+            lookups: self.lookups(),
+            errors: self.errors(),
+            timeouts: self.timeouts.load(Ordering::Relaxed) + timeouts,
+            retries,
+            rcodes: self.rcodes().await,
+            wildcards: Wildcards {
+                hosts: self.wildcard_hosts.load(Ordering::Relaxed),
+                zones: self.wildcard_zones.load(Ordering::Relaxed),
+            },
+            resolvers,
+        }
+    }
+
+    /// The same totals in the Prometheus text format, along with the latency histograms and the
+    /// depths of the channels. `retries` and `timeouts` are the ones from the raw engine.
+    pub(crate) async fn prometheus(&self, (retries, timeouts): (usize, usize)) -> String {
+        let mut out = String::new();
+        let counters = [
+            ("hosts", "Hosts that were resolved.", self.hosts()),
+            ("queries", "Queries that were sent.", self.sent()),
+            (
+                "timeouts",
+                "Lookups that got no response.",
+                self.timeouts.load(Ordering::Relaxed) + timeouts,
+            ),
+            ("retries", "Queries the raw engine sent again.", retries),
+        ];
+        for (name, help, value) in counters.iter() {
+            let _ = writeln!(out, "# HELP ree_{}_total {}", name, help);
+            let _ = writeln!(out, "# TYPE ree_{}_total counter", name);
+            let _ = writeln!(out, "ree_{}_total {}", name, value);
+        }
+
+        let _ = writeln!(
+            out,
+            "# HELP ree_lookups_total Lookups that were answered, by response code."
+        );
+        let _ = writeln!(out, "# TYPE ree_lookups_total counter");
+        for (rcode, count) in self.rcodes().await {
+            let _ = writeln!(
+                out,
+                "ree_lookups_total{{rcode=\"{}\"}} {}",
+                label(&rcode),
+                count
+            );
+        }
+
+        let name = "ree_resolver_latency_seconds";
+        let _ = writeln!(
+            out,
+            "# HELP {} How long each resolver took to answer.",
+            name
+        );
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        let latencies = self.latencies.lock().await;
+        let mut resolvers: Vec<&IpAddr> = latencies.keys().collect();
+        resolvers.sort();
+        for resolver in resolvers {
+            let histogram = &latencies[resolver];
+            let buckets = histogram
+                .cumulative()
+                .filter_map(|(bound, total)| bound.map(|bound| (bound, total)))
+                .filter(|(bound, _)| PROMETHEUS_BUCKETS.contains(bound));
+            for (bound, total) in buckets {
+                let le = bound as f64 / 1000.0;
+                let _ = writeln!(
+                    out,
+                    "{}_bucket{{resolver=\"{}\",le=\"{}\"}} {}",
+                    name, resolver, le, total
+                );
+            }
+            let _ = writeln!(
+                out,
+                "{}_bucket{{resolver=\"{}\",le=\"+Inf\"}} {}",
+                name, resolver, histogram.count
+            );
+            let _ = writeln!(
+                out,
+                "{}_sum{{resolver=\"{}\"}} {}",
+                name,
+                resolver,
+                histogram.sum.as_secs_f64()
+            );
+            let _ = writeln!(
+                out,
+                "{}_count{{resolver=\"{}\"}} {}",
+                name, resolver, histogram.count
+            );
+        }
+
+        let _ = writeln!(
+            out,
+            "# HELP ree_queue_depth Messages waiting in the channel between the lookups and the results."
+        );
+        let _ = writeln!(out, "# TYPE ree_queue_depth gauge");
+        let _ = writeln!(
+            out,
+            "ree_queue_depth{{queue=\"records\"}} {}",
+            self.records_queue.get()
+        );
+        out
+    }
+}
+
+/// Escapes a label value for the Prometheus text format.
+fn label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct Report {
+    wall_time_secs: f64,
+    hosts: usize,
+    queries: usize,
+    lookups: usize,
+    errors: usize,
+    timeouts: usize,
+    retries: usize,
+    rcodes: BTreeMap<String, usize>,
+    wildcards: Wildcards,
+    resolvers: BTreeMap<String, Latency>,
+}
+
+#[derive(Debug, Serialize)]
+struct Wildcards {
+    hosts: usize,
+    zones: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct Latency {
+    answered: u64,
+    mean_ms: f64,
+    p50_ms: f64,
+    p90_ms: f64,
+    p99_ms: f64,
+    max_ms: f64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    async fn lookup(stats: &Stats, rcode: ResponseCode) {
+        let mut responses = VecDeque::new();
+        responses.push_back(ResolveResponse::Error {
+            query: "www.example.com.".to_owned(),
+            response_code: rcode.to_string(),
+            authority: None,
+            subnet: None,
+        });
+        stats.lookup(&responses).await;
+    }
+
+    #[tokio::test]
+    async fn the_report_has_the_totals_and_the_latencies_of_each_resolver() {
+        let stats = Stats::default();
+        stats.host_done();
+        stats.host_done();
+        stats.queries(5);
+        stats.timeouts(1);
+        lookup(&stats, ResponseCode::NoError).await;
+        lookup(&stats, ResponseCode::NXDomain).await;
+        // Lookups without any responses were counted as timeouts.
+        stats.lookup(&VecDeque::new()).await;
+        stats.wildcards(&HashMap::from([
+            ("a.example.com.".to_owned(), "example.com.".to_owned()),
+            ("b.example.com.".to_owned(), "example.com.".to_owned()),
+        ]));
+        let resolver = IpAddr::from([192, 0, 2, 1]);
+        for ms in [4, 4, 20, 300] {
+            stats.latency(resolver, Duration::from_millis(ms)).await;
+        }
+
+        let mut report = serde_json::to_value(stats.report((3, 2)).await).unwrap();
+        let wall_time = report.as_object_mut().unwrap().remove("wall_time_secs");
+        assert!(wall_time.unwrap().is_f64());
+        assert_eq!(
+            report,
+            json!({
+                "hosts": 2,
+                "queries": 5,
+                "lookups": 2,
+                "errors": 1,
+                "timeouts": 3,
+                "retries": 3,
+                "rcodes": {"No Error": 1, "Non-Existent Domain": 1},
+                "wildcards": {"hosts": 2, "zones": 1},
+                "resolvers": {
+                    "192.0.2.1": {
+                        "answered": 4,
+                        "mean_ms": 82.0,
+                        "p50_ms": 4.0,
+                        "p90_ms": 300.0,
+                        "p99_ms": 300.0,
+                        "max_ms": 300.0
+                    }
+                }
+            })
+        );
+    }
+}
@@ -0,0 +1,72 @@
+//! A nameserver stand-in for the tests, answering from a function over udp on localhost.
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::str::FromStr;
+use trust_dns_proto::op::{Message, MessageType, ResponseCode};
+use trust_dns_proto::rr::rdata::SOA;
+use trust_dns_proto::rr::{Name, RData, Record, RecordType};
+use trust_dns_resolver::config::{NameServerConfigGroup, ResolverConfig};
+
+/// The config of a client that sends its queries to the stand-in at `nameserver`.
+pub(crate) fn config(nameserver: SocketAddr) -> ResolverConfig {
+    let group = NameServerConfigGroup::from_ips_clear(&[nameserver.ip()], nameserver.port(), false);
+    ResolverConfig::from_parts(None, vec![], group)
+}
+
+pub(crate) fn name(name: &str) -> Name {
+    Name::from_str(name).unwrap()
+}
+
+pub(crate) fn a(owner: &Name, ip: [u8; 4]) -> Record {
+    Record::from_rdata(owner.clone(), 300, RData::A(Ipv4Addr::from(ip)))
+}
+
+/// Starts a nameserver on a free port that answers every query with the response code and
+/// answers `answer` returns for it, and returns its address. Responses without answers carry
+/// the SOA of `zone`, like the negative answers of a real nameserver.
+pub(crate) fn nameserver<F>(zone: &str, answer: F) -> SocketAddr
+where
+    F: Fn(&Name, RecordType) -> (ResponseCode, Vec<Record>) + Send + 'static,
+{
+    let socket = UdpSocket::bind(SocketAddr::from((Ipv4Addr::LOCALHOST, 0))).unwrap();
+    let addr = socket.local_addr().unwrap();
+    let zone = name(zone);
+    let soa = Record::from_rdata(
+        zone.clone(),
+        300,
+        RData::SOA(SOA::new(
+            name("ns.invalid."),
+            name("admin.invalid."),
+            1,
+            3600,
+            600,
+            86400,
+            300,
+        )),
+    );
+
+    std::thread::spawn(move || {
+        let mut buf = [0; 4096];
+        loop {
+            let (len, from) = match socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(_) => continue,
+            };
+            let mut message = match Message::from_vec(&buf[..len]) {
+                Ok(message) => message,
+                Err(_) => continue,
+            };
+            let query = message.queries()[0].clone();
+            let (code, answers) = answer(query.name(), query.query_type());
+            message
+                .set_message_type(MessageType::Response)
+                .set_recursion_available(true)
+                .set_response_code(code)
+                .insert_answers(answers);
+            if message.answers().is_empty() {
+                message.insert_name_servers(vec![soa.clone()]);
+            }
+            let _ = socket.send_to(&message.to_vec().unwrap(), from);
+        }
+    });
+    addr
+}
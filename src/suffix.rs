@@ -0,0 +1,83 @@
+use std::collections::HashSet;
+use std::sync::OnceLock;
+
+/// A snapshot of the Public Suffix List, see `data/public_suffix_list.dat`.
+const LIST: &str = include_str!("../data/public_suffix_list.dat");
+
+/// The rules of the Public Suffix List, the suffixes that anyone can register names under like
+/// `com` and `co.uk`. The list's internationalized rules are in unicode, so they don't match the
+/// punycode names we resolve.
+struct Rules {
+    suffixes: HashSet<&'static str>,
+    // The parents of the `*.` rules, e.g `ck` for `*.ck`.
+    wildcards: HashSet<&'static str>,
+    exceptions: HashSet<&'static str>,
+}
+
+impl Rules {
+    fn parse(list: &'static str) -> Self {
+        let mut rules = Self {
+            suffixes: HashSet::new(),
+            wildcards: HashSet::new(),
+            exceptions: HashSet::new(),
+        };
+        let lines = list
+            .lines()
+            .filter_map(|line| line.split_whitespace().next())
+            .filter(|rule| !rule.starts_with("//"));
+        for rule in lines {
+            if let Some(exception) = rule.strip_prefix('!') {
+                rules.exceptions.insert(exception);
+            } else if let Some(parent) = rule.strip_prefix("*.") {
+                rules.wildcards.insert(parent);
+            } else {
+                rules.suffixes.insert(rule);
+            }
+        }
+        rules
+    }
+
+    fn is_public_suffix(&self, name: &str) -> bool {
+        if self.exceptions.contains(name) {
+            return false;
+        }
+        match name.split_once('.') {
+            Some((_, parent)) => self.suffixes.contains(name) || self.wildcards.contains(parent),
+            // Any top level domain is a public suffix, listed or not.
+            None => true,
+        }
+    }
+}
+
+/// Returns whether `name` is a public suffix, so the names directly under it belong to
+/// different registrants, e.g `co.uk` but not `example.co.uk`.
+pub(crate) fn is_public_suffix(name: &str) -> bool {
+    static RULES: OnceLock<Rules> = OnceLock::new();
+    let name = name.trim_end_matches('.').to_lowercase();
+    RULES
+        .get_or_init(|| Rules::parse(LIST))
+        .is_public_suffix(&name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn public_suffixes() {
+        assert!(is_public_suffix("com"));
+        assert!(is_public_suffix("co.uk"));
+        assert!(is_public_suffix("com.au."));
+        assert!(is_public_suffix("CO.UK"));
+        assert!(!is_public_suffix("example.com"));
+        assert!(!is_public_suffix("example.co.uk"));
+    }
+
+    #[test]
+    fn wildcards_and_their_exceptions() {
+        // `*.ck` with `!www.ck`
+        assert!(is_public_suffix("anything.ck"));
+        assert!(!is_public_suffix("www.ck"));
+        assert!(!is_public_suffix("example.anything.ck"));
+    }
+}
@@ -0,0 +1,246 @@
+use crate::data::{normalize, ResolveResponse, ResultsCache};
+use std::collections::HashSet;
+use std::collections::VecDeque;
+use tracing::info;
+use trust_dns_proto::op::ResponseCode;
+
+/// Services that are commonly left behind as dangling CNAME targets, and can be claimed by anyone
+/// once the resource they pointed to has been deleted.
+const DEFAULT_FINGERPRINTS: &[(&str, &str)] = &[
+    ("*.s3.amazonaws.com", "AWS S3"),
+    ("*.s3-website-us-east-1.amazonaws.com", "AWS S3"),
+    ("*.elasticbeanstalk.com", "AWS Elastic Beanstalk"),
+    ("*.cloudfront.net", "AWS CloudFront"),
+    ("*.azurewebsites.net", "Azure App Service"),
+    ("*.cloudapp.net", "Azure Cloud Services"),
+    ("*.cloudapp.azure.com", "Azure Virtual Machines"),
+    ("*.trafficmanager.net", "Azure Traffic Manager"),
+    ("*.blob.core.windows.net", "Azure Blob Storage"),
+    ("*.azureedge.net", "Azure CDN"),
+    ("*.herokuapp.com", "Heroku"),
+    ("*.herokudns.com", "Heroku"),
+    ("*.github.io", "GitHub Pages"),
+    ("*.bitbucket.io", "Bitbucket"),
+    ("*.netlify.app", "Netlify"),
+    ("*.surge.sh", "Surge"),
+    ("*.ghost.io", "Ghost"),
+    ("*.myshopify.com", "Shopify"),
+    ("*.pantheonsite.io", "Pantheon"),
+    ("*.wpengine.com", "WP Engine"),
+    ("*.zendesk.com", "Zendesk"),
+    ("*.readme.io", "ReadMe"),
+    ("*.statuspage.io", "Statuspage"),
+    ("*.unbouncepages.com", "Unbounce"),
+    ("*.helpscoutdocs.com", "Help Scout"),
+    ("*.fastly.net", "Fastly"),
+];
+
+/// A list of patterns for CNAME targets that belong to a service, where `*.` matches any name
+/// under the rest of the pattern.
+#[derive(Debug)]
+pub struct Fingerprints {
+    patterns: Vec<(String, String)>,
+}
+
+impl Default for Fingerprints {
+    fn default() -> Self {
+        Self {
+            patterns: DEFAULT_FINGERPRINTS
+                .iter()
+                .map(|(pattern, service)| (pattern.to_string(), service.to_string()))
+                .collect(),
+        }
+    }
+}
+
+impl Fingerprints {
+    /// Adds the fingerprints in the file at `path` to the default list. Each line is a pattern
+    /// and the name of the service separated by a comma, e.g `*.herokuapp.com,Heroku`.
+    pub fn load(mut self, path: &str) -> Self {
+        let file = std::fs::read_to_string(path).expect("unable to read fingerprints");
+        for line in file.lines() {
+            if let Some((pattern, service)) = line.split_once(',') {
+                self.patterns
+                    .push((pattern.trim().to_lowercase(), service.trim().to_owned()));
+            }
+        }
+        self
+    }
+
+    /// Returns the service the target belongs to, if any of the patterns match it.
+    fn service(&self, target: &str) -> Option<&str> {
+        let target = target.trim_end_matches('.').to_lowercase();
+        self.patterns
+            .iter()
+            .find(|(pattern, _)| match pattern.strip_prefix("*.") {
+                Some(suffix) => target.ends_with(&format!(".{}", suffix)),
+                None => &target == pattern,
+            })
+            .map(|(_, service)| service.as_str())
+    }
+
+    /// Correlates the CNAME records in the cache with the NXDOMAIN errors for their targets, and
+    /// adds a `Takeover` for every CNAME whose target doesn't exist, along with the service it
+    /// belongs to if it matches one of the patterns. A target that exists isn't flagged, even if
+    /// it belongs to a service, since most of them are serving something.
+    pub(crate) async fn check(&self, cache: &ResultsCache) {
+        let records = cache.records().await;
+        let nxdomain = ResponseCode::NXDomain.to_string();
+        // The error is reported for the name that doesn't exist, which for a dangling CNAME is
+        // its target rather than the host that was looked up.
+        let dangling: HashSet<String> = records
+            .values()
+            .filter_map(|record| match record {
+                ResolveResponse::Error {
+                    query,
+                    response_code,
+                    ..
+                } if response_code == &nxdomain => Some(normalize(query)),
+                _ => None,
+            })
+            .collect();
+
+        // A host can go through several CNAMEs, each of them is checked.
+        let targets: HashSet<(&String, &String)> = records
+            .values()
+            .filter_map(|record| match record {
+                ResolveResponse::Record {
+                    query, name, kind, ..
+                } if kind == "CNAME" => Some((query, name)),
+                _ => None,
+            })
+            .collect();
+
+        let mut takeovers: VecDeque<ResolveResponse> = targets
+            .into_iter()
+            .filter(|(_, target)| dangling.contains(&normalize(target)))
+            .map(|(query, target)| {
+                let service = self.service(target);
+                info!("{} may be vulnerable to a takeover via {}", query, target);
+                ResolveResponse::Takeover {
+                    query: query.clone(),
+                    target: target.clone(),
+                    service: service.map(str::to_owned),
+                }
+            })
+            .collect();
+
+        cache.insert(&mut takeovers).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wire;
+    use std::str::FromStr;
+    use std::sync::Arc;
+    use trust_dns_proto::op::Message;
+    use trust_dns_proto::rr::{Name, RData, Record};
+
+    fn cname(name: &str, target: &str) -> Record {
+        Record::from_rdata(
+            Name::from_str(name).unwrap(),
+            300,
+            RData::CNAME(Name::from_str(target).unwrap()),
+        )
+    }
+
+    /// What a resolver answers for `query`, after following the CNAMEs in `answers`.
+    fn response(
+        query: &str,
+        code: ResponseCode,
+        answers: Vec<Record>,
+    ) -> VecDeque<ResolveResponse> {
+        let mut message = Message::new();
+        message.set_response_code(code).insert_answers(answers);
+        wire::convert(&Arc::new(query.to_owned()), &message)
+    }
+
+    async fn takeovers(mut responses: VecDeque<ResolveResponse>) -> Vec<(String, Option<String>)> {
+        let cache = ResultsCache::new();
+        cache.insert(&mut responses).await;
+        Fingerprints::default().check(&cache).await;
+        let mut takeovers: Vec<(String, Option<String>)> = cache
+            .records()
+            .await
+            .into_values()
+            .filter_map(|record| match record {
+                ResolveResponse::Takeover { query, service, .. } => Some((query, service)),
+                _ => None,
+            })
+            .collect();
+        takeovers.sort();
+        takeovers
+    }
+
+    #[tokio::test]
+    async fn dangling_cnames_are_flagged() {
+        let mut responses = response(
+            "old.example.com.",
+            ResponseCode::NXDomain,
+            vec![cname("old.example.com.", "gone.herokuapp.com.")],
+        );
+        responses.extend(response(
+            "www.example.com.",
+            ResponseCode::NXDomain,
+            vec![
+                cname("www.example.com.", "www.example.net."),
+                cname("www.example.net.", "deleted.example.org."),
+            ],
+        ));
+        assert_eq!(
+            takeovers(responses).await,
+            vec![
+                ("old.example.com.".to_owned(), Some("Heroku".to_owned())),
+                ("www.example.com.".to_owned(), None),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn dangling_cnames_from_the_resolver_library_are_flagged() {
+        use trust_dns_proto::op::Query;
+        use trust_dns_proto::rr::RecordType;
+        use trust_dns_resolver::error::ResolveErrorKind;
+
+        // The library follows the CNAME and reports the error for its target.
+        let query = Arc::new("old.example.com.".to_owned());
+        let mut responses = VecDeque::new();
+        responses.push_back(ResolveResponse::new(
+            &cname("old.example.com.", "gone.s3.amazonaws.com."),
+            Arc::clone(&query),
+        ));
+        let error = ResolveErrorKind::NoRecordsFound {
+            query: Query::query(
+                Name::from_str("gone.s3.amazonaws.com.").unwrap(),
+                RecordType::A,
+            ),
+            soa: None,
+            negative_ttl: None,
+            response_code: ResponseCode::NXDomain,
+            trusted: true,
+        };
+        responses.extend(ResolveResponse::from_error(error.into()));
+        assert_eq!(
+            takeovers(responses).await,
+            vec![("old.example.com.".to_owned(), Some("AWS S3".to_owned()))]
+        );
+    }
+
+    #[tokio::test]
+    async fn services_that_resolve_are_not_flagged() {
+        let responses = response(
+            "cdn.example.com.",
+            ResponseCode::NoError,
+            vec![cname("cdn.example.com.", "d111111abcdef8.cloudfront.net.")],
+        );
+        assert!(takeovers(responses).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn hosts_that_do_not_exist_are_not_flagged() {
+        let responses = response("nope.example.com.", ResponseCode::NXDomain, vec![]);
+        assert!(takeovers(responses).await.is_empty());
+    }
+}
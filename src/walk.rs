@@ -0,0 +1,247 @@
+use crate::resolver::canary;
+use crate::wire;
+use crate::{ReeError, Result};
+use data_encoding::{BASE32HEX_NOPAD, HEXLOWER};
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tracing::info;
+use trust_dns_proto::op::{Edns, Message, ResponseCode};
+use trust_dns_proto::rr::dnssec::rdata::{DNSSECRData, DNSSECRecordType};
+use trust_dns_proto::rr::{Name, RData, Record, RecordType};
+
+/// The most random names looked up to collect the NSEC3 records of a zone. Each denial only
+/// reveals the hashes around the hash of the name, so it takes a lot of them to see every hash.
+const NSEC3_QUERIES: usize = 512;
+
+/// How many of the random names are looked up at once.
+const NSEC3_BATCH: usize = 16;
+
+/// Everything a walk of a zone found. With NSEC the names themselves are found, with NSEC3 only
+/// their hashes, in the `hash:.zone:salt:iterations` format hashcat expects for cracking.
+#[derive(Debug, Default)]
+pub(crate) struct Walk {
+    pub(crate) names: Vec<String>,
+    pub(crate) hashes: Vec<String>,
+}
+
+/// Walks the NSEC chain of `zone` using the nameserver at `server`, or collects its NSEC3 hashes
+/// if the zone is using NSEC3 instead.
+pub(crate) async fn walk(server: SocketAddr, zone: &str, wait: Duration) -> Result<Walk> {
+    let apex = Name::from_ascii(format!("{}.", zone.trim_end_matches('.')))?.to_lowercase();
+
+    if next_name(server, &apex, wait).await?.is_some() {
+        return walk_nsec(server, &apex, wait).await;
+    }
+
+    let nsec3param = RecordType::DNSSEC(DNSSECRecordType::NSEC3PARAM);
+    let response = wire::udp_query(server, &request(&apex, nsec3param)?, wait).await?;
+    if response
+        .answers()
+        .iter()
+        .any(|r| r.record_type() == nsec3param)
+    {
+        return collect_nsec3(server, &apex, wait).await;
+    }
+
+    Err(ReeError::Walk(format!(
+        "{} has no NSEC or NSEC3 records",
+        apex
+    )))
+}
+
+/// Follows the chain from the apex until it wraps back around to it. Every owner along the way
+/// is a name in the zone.
+async fn walk_nsec(server: SocketAddr, apex: &Name, wait: Duration) -> Result<Walk> {
+    let mut walk = Walk::default();
+    let mut seen = HashSet::new();
+    let mut current = apex.clone();
+
+    while seen.insert(current.clone()) {
+        // Wildcards aren't names we can look up, and the hosts we resolve don't end in a dot.
+        if !current.is_wildcard() {
+            walk.names
+                .push(current.to_utf8().trim_end_matches('.').to_owned());
+        }
+
+        current = match next_name(server, &current, wait).await? {
+            Some(next) if apex.zone_of(&next) => next,
+            _ => break,
+        };
+    }
+
+    info!("walked {} names of {}", walk.names.len(), apex);
+    Ok(walk)
+}
+
+/// Returns the next name in the NSEC chain after `name`, or `None` if it has no NSEC record.
+async fn next_name(server: SocketAddr, name: &Name, wait: Duration) -> Result<Option<Name>> {
+    let nsec = RecordType::DNSSEC(DNSSECRecordType::NSEC);
+    let response = wire::udp_query(server, &request(name, nsec)?, wait).await?;
+    if response.response_code() != ResponseCode::NoError {
+        return Err(ReeError::Walk(response.response_code().to_string()));
+    }
+
+    Ok(response
+        .answers()
+        .iter()
+        .filter(|record| record.name().to_lowercase() == *name)
+        .find_map(|record| match record.rdata() {
+            RData::DNSSEC(DNSSECRData::NSEC(nsec)) => Some(nsec.next_domain_name().to_lowercase()),
+            _ => None,
+        }))
+}
+
+/// Looks up random names in the zone and keeps the NSEC3 records that deny them, until the
+/// hashes we've seen link up into the whole chain or we run out of queries.
+async fn collect_nsec3(server: SocketAddr, apex: &Name, wait: Duration) -> Result<Walk> {
+    // The hash of every owner in the chain, and the hash of the owner after it.
+    let mut chain: HashMap<String, String> = HashMap::new();
+    let mut params = None;
+
+    for _ in 0..NSEC3_QUERIES / NSEC3_BATCH {
+        let lookups = (0..NSEC3_BATCH).map(|_| async move {
+            let name = Name::from_ascii(canary())?.append_domain(apex);
+            wire::udp_query(server, &request(&name, RecordType::A)?, wait).await
+        });
+
+        for response in futures::future::join_all(lookups).await {
+            // A single lost response isn't worth giving up the walk over.
+            let response = match response {
+                Ok(response) => response,
+                Err(_) => continue,
+            };
+            for (hash, next, salt, iterations) in response.name_servers().iter().filter_map(nsec3) {
+                params = Some((salt, iterations));
+                chain.insert(hash, next);
+            }
+        }
+
+        if !chain.is_empty() && chain.values().all(|next| chain.contains_key(next)) {
+            break;
+        }
+    }
+
+    let (salt, iterations) = params
+        .ok_or_else(|| ReeError::Walk(format!("no NSEC3 records were returned for {}", apex)))?;
+    let mut hashes: Vec<String> = chain
+        .keys()
+        .map(|hash| hashcat(hash, apex, &salt, iterations))
+        .collect();
+    hashes.sort();
+
+    info!("collected {} NSEC3 hashes of {}", hashes.len(), apex);
+    Ok(Walk {
+        names: Vec::new(),
+        hashes,
+    })
+}
+
+/// Pulls the owner hash, next hash, salt and iterations out of an NSEC3 record. The hashes are
+/// in lowercase base32hex and the salt in hex.
+fn nsec3(record: &Record) -> Option<(String, String, String, u16)> {
+    match record.rdata() {
+        RData::DNSSEC(DNSSECRData::NSEC3(nsec3)) => {
+            let owner = record.name().to_lowercase();
+            let hash = String::from_utf8_lossy(owner.iter().next()?).into_owned();
+            let next = BASE32HEX_NOPAD
+                .encode(nsec3.next_hashed_owner_name())
+                .to_lowercase();
+            Some((
+                hash,
+                next,
+                HEXLOWER.encode(nsec3.salt()),
+                nsec3.iterations(),
+            ))
+        }
+        _ => None,
+    }
+}
+
+/// Formats an NSEC3 hash of a name in `zone` the way hashcat's mode 8300 reads it, e.g
+/// `hash:.example.com:salt:iterations`.
+fn hashcat(hash: &str, zone: &Name, salt: &str, iterations: u16) -> String {
+    let zone = zone.to_utf8();
+    format!(
+        "{}:.{}:{}:{}",
+        hash,
+        zone.trim_end_matches('.'),
+        salt,
+        iterations
+    )
+}
+
+/// Builds a query that asks for the DNSSEC records along with the answers.
+fn request(name: &Name, kind: RecordType) -> Result<Message> {
+    let mut message = wire::query_message(&name.to_utf8(), kind, true)?;
+    let mut edns = Edns::new();
+    edns.set_dnssec_ok(true)
+        .set_max_payload(wire::MAX_UDP_SIZE as u16);
+    message.set_edns(edns);
+    Ok(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stub;
+    use trust_dns_proto::rr::dnssec::rdata::{NSEC, NSEC3};
+    use trust_dns_proto::rr::dnssec::Nsec3HashAlgorithm;
+
+    /// Serves a zone whose NSEC chain goes from the apex to `*.example.test`, `www` and back.
+    fn nameserver() -> SocketAddr {
+        stub::nameserver("example.test.", |query: &Name, kind| {
+            let nsec = RecordType::DNSSEC(DNSSECRecordType::NSEC);
+            let next = match query.to_utf8().as_str() {
+                "example.test." => "*.example.test.",
+                "*.example.test." => "www.example.test.",
+                "www.example.test." => "example.test.",
+                _ => return (ResponseCode::NXDomain, vec![]),
+            };
+            if kind != nsec {
+                return (ResponseCode::NoError, vec![]);
+            }
+            let rdata = RData::DNSSEC(DNSSECRData::NSEC(NSEC::new(
+                stub::name(next),
+                vec![RecordType::A, nsec],
+            )));
+            (
+                ResponseCode::NoError,
+                vec![Record::from_rdata(query.clone(), 300, rdata)],
+            )
+        })
+    }
+
+    #[tokio::test]
+    async fn the_nsec_chain_is_followed_back_to_the_apex() {
+        let walk = walk(nameserver(), "example.test", Duration::from_secs(2))
+            .await
+            .unwrap();
+        // The wildcard isn't a name that can be looked up.
+        assert_eq!(walk.names, vec!["example.test", "www.example.test"]);
+        assert!(walk.hashes.is_empty());
+    }
+
+    #[test]
+    fn nsec3_records_are_written_for_hashcat() {
+        let owner = stub::name("2vptu5timamqttgl4luu9kg21e0aor3s.example.test.");
+        let rdata = RData::DNSSEC(DNSSECRData::NSEC3(NSEC3::new(
+            Nsec3HashAlgorithm::SHA1,
+            false,
+            10,
+            vec![0xaa, 0xbb],
+            BASE32HEX_NOPAD
+                .decode(b"35MTHGPGCU1QG68FAB165KLNSNK3DPVL")
+                .unwrap(),
+            vec![RecordType::A],
+        )));
+        let record = Record::from_rdata(owner, 300, rdata);
+
+        let (hash, next, salt, iterations) = nsec3(&record).unwrap();
+        assert_eq!(next, "35mthgpgcu1qg68fab165klnsnk3dpvl");
+        assert_eq!(
+            hashcat(&hash, &stub::name("example.test."), &salt, iterations),
+            "2vptu5timamqttgl4luu9kg21e0aor3s:.example.test:aabb:10"
+        );
+    }
+}
@@ -0,0 +1,246 @@
+use crate::data::{self, ResolveResponse};
+use crate::Result;
+use ipnet::IpNet;
+use std::collections::VecDeque;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::time::{timeout, Instant};
+use trust_dns_proto::op::{Edns, Message, MessageType, OpCode, Query, ResponseCode};
+use trust_dns_proto::rr::rdata::opt::{EdnsCode, EdnsOption};
+use trust_dns_proto::rr::{Name, RecordType};
+
+// Large enough for any response sent over udp, including ones using edns.
+pub(crate) const MAX_UDP_SIZE: usize = 4096;
+
+/// Builds a query message for `name` with a random id.
+pub(crate) fn query_message(
+    name: &str,
+    kind: RecordType,
+    recursion_desired: bool,
+) -> Result<Message> {
+    let mut message = Message::new();
+    message
+        .set_id(rand::random())
+        .set_message_type(MessageType::Query)
+        .set_op_code(OpCode::Query)
+        .set_recursion_desired(recursion_desired)
+        .add_query(Query::query(Name::from_str(name)?, kind));
+    Ok(message)
+}
+
+/// Adds the EDNS Client Subnet option to `message`, so the server answers as if the query came
+/// from a client in `subnet`.
+pub(crate) fn set_client_subnet(message: &mut Message, subnet: &IpNet) {
+    let subnet = subnet.trunc();
+    let (family, address): (u16, Vec<u8>) = match subnet.addr() {
+        IpAddr::V4(ip) => (1, ip.octets().to_vec()),
+        IpAddr::V6(ip) => (2, ip.octets().to_vec()),
+    };
+    // Only the bytes covered by the prefix are sent.
+    let len = (subnet.prefix_len() as usize).div_ceil(8);
+
+    let mut option = family.to_be_bytes().to_vec();
+    option.push(subnet.prefix_len());
+    // The scope is always zero in queries, the server sets it in its response.
+    option.push(0);
+    option.extend_from_slice(&address[..len]);
+
+    let mut edns = Edns::new();
+    edns.set_max_payload(MAX_UDP_SIZE as u16);
+    edns.options_mut()
+        .insert(EdnsOption::Unknown(u16::from(EdnsCode::Subnet), option));
+    message.set_edns(edns);
+}
+
+/// Sends `request` to `server` over udp and waits up to `wait` for the response with a matching
+/// id. Anything else that arrives on the socket in the meantime is ignored.
+pub(crate) async fn udp_query(
+    server: SocketAddr,
+    request: &Message,
+    wait: Duration,
+) -> Result<Message> {
+    let local: IpAddr = match server {
+        SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    };
+    let mut socket = UdpSocket::bind(SocketAddr::new(local, 0)).await?;
+    socket.send_to(&request.to_vec()?, server).await?;
+
+    let deadline = Instant::now() + wait;
+    let mut buf = [0u8; MAX_UDP_SIZE];
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let (len, from) = timeout(remaining, socket.recv_from(&mut buf)).await??;
+        if from != server {
+            continue;
+        }
+        if let Ok(response) = Message::from_vec(&buf[..len]) {
+            if response.id() == request.id() {
+                return Ok(response);
+            }
+        }
+    }
+}
+
+/// Asks `server` for the A, AAAA and CNAME records of `host`, optionally with the EDNS Client
+/// Subnet option for `subnet`. Returns the responses along with the number of queries sent.
+pub(crate) async fn lookup(
+    server: SocketAddr,
+    host: &str,
+    recursion_desired: bool,
+    subnet: Option<&IpNet>,
+    wait: Duration,
+) -> (VecDeque<ResolveResponse>, usize) {
+    let query = Arc::new(format!("{}.", host.trim_end_matches('.')));
+    let requests = match requests(&query, recursion_desired, subnet) {
+        Ok(requests) => requests,
+        Err(_) => return (VecDeque::new(), 0),
+    };
+
+    let mut received = Vec::with_capacity(requests.len());
+    for request in &requests {
+        received.push(udp_query(server, request, wait).await);
+    }
+    (responses(&query, received, subnet), requests.len())
+}
+
+/// Builds the A, AAAA and CNAME queries for `query`, optionally with the EDNS Client Subnet
+/// option for `subnet`.
+pub(crate) fn requests(
+    query: &str,
+    recursion_desired: bool,
+    subnet: Option<&IpNet>,
+) -> Result<Vec<Message>> {
+    [RecordType::A, RecordType::AAAA, RecordType::CNAME]
+        .iter()
+        .map(|kind| {
+            let mut request = query_message(query, *kind, recursion_desired)?;
+            if let Some(subnet) = subnet {
+                set_client_subnet(&mut request, subnet);
+            }
+            Ok(request)
+        })
+        .collect()
+}
+
+/// Converts the responses to the `requests` for `query` into the responses we write out,
+/// including the chains of any CNAMEs. Every response is tagged with the subnet it was asked for.
+pub(crate) fn responses(
+    query: &Arc<String>,
+    received: Vec<Result<Message>>,
+    subnet: Option<&IpNet>,
+) -> VecDeque<ResolveResponse> {
+    let mut responses = VecDeque::new();
+    // Like the resolver path, timeouts and io errors don't make it into the results.
+    for response in received.into_iter().flatten() {
+        responses.extend(convert(query, &response));
+        responses.extend(ResolveResponse::chains(response.answers(), query));
+    }
+
+    if let Some(subnet) = subnet {
+        for response in responses.iter_mut() {
+            response.set_subnet(subnet);
+        }
+    }
+    responses
+}
+
+/// Converts the answers in `response` into the responses we write out, and adds an `Error` with
+/// its response code when there aren't any or the name doesn't exist.
+pub(crate) fn convert(query: &Arc<String>, response: &Message) -> VecDeque<ResolveResponse> {
+    let code = response.response_code();
+    // An NXDOMAIN still carries the CNAMEs that led to the name that doesn't exist, which is how
+    // a dangling CNAME shows up.
+    let mut responses: VecDeque<ResolveResponse> = match code {
+        ResponseCode::NoError | ResponseCode::NXDomain => response
+            .answers()
+            .iter()
+            .map(|record| ResolveResponse::new(record, Arc::clone(query)))
+            .collect(),
+        _ => VecDeque::new(),
+    };
+    if code == ResponseCode::NoError && !responses.is_empty() {
+        return responses;
+    }
+
+    // Like the resolver library, the error is reported for the name at the end of the CNAMEs.
+    responses.push_back(ResolveResponse::Error {
+        query: data::cname_chain(response.answers(), query)
+            .pop()
+            .unwrap_or_default(),
+        response_code: code.to_string(),
+        authority: None,
+        subnet: None,
+    });
+    responses
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stub;
+
+    /// The family, prefix length, scope and address bytes of the client subnet in `message`.
+    fn client_subnet(message: &Message) -> (u16, u8, u8, Vec<u8>) {
+        let option = message.edns().unwrap().option(EdnsCode::Subnet).unwrap();
+        match option {
+            EdnsOption::Unknown(_, data) => (
+                u16::from_be_bytes([data[0], data[1]]),
+                data[2],
+                data[3],
+                data[4..].to_vec(),
+            ),
+            _ => panic!("unexpected option {:?}", option),
+        }
+    }
+
+    #[test]
+    fn client_subnets_only_send_the_bytes_of_their_prefix() {
+        let mut message = query_message("example.test.", RecordType::A, true).unwrap();
+
+        set_client_subnet(&mut message, &"192.0.2.77/24".parse().unwrap());
+        assert_eq!(client_subnet(&message), (1, 24, 0, vec![192, 0, 2]));
+
+        set_client_subnet(&mut message, &"198.51.100.255/20".parse().unwrap());
+        assert_eq!(client_subnet(&message), (1, 20, 0, vec![198, 51, 96]));
+
+        set_client_subnet(&mut message, &"2001:db8:abcd:12ff::1/56".parse().unwrap());
+        assert_eq!(
+            client_subnet(&message),
+            (2, 56, 0, vec![0x20, 0x01, 0x0d, 0xb8, 0xab, 0xcd, 0x12])
+        );
+
+        set_client_subnet(&mut message, &"2001:db8::/0".parse().unwrap());
+        assert_eq!(client_subnet(&message), (2, 0, 0, vec![]));
+    }
+
+    #[tokio::test]
+    async fn errors_are_kept_apart_by_their_subnet() {
+        let server = stub::nameserver("example.test.", |_: &Name, _| {
+            (ResponseCode::NXDomain, vec![])
+        });
+        let mut keys = Vec::new();
+        for subnet in ["192.0.2.0/24", "198.51.100.0/24"] {
+            let subnet: IpNet = subnet.parse().unwrap();
+            let (responses, sent) = lookup(
+                server,
+                "gone.example.test.",
+                true,
+                Some(&subnet),
+                Duration::from_secs(2),
+            )
+            .await;
+            assert_eq!(sent, 3);
+            let error = responses
+                .iter()
+                .find(|r| matches!(r, ResolveResponse::Error { .. }))
+                .unwrap();
+            keys.push(error.key());
+        }
+        assert_eq!(keys[0].4, "192.0.2.0/24");
+        assert_ne!(keys[0], keys[1]);
+    }
+}
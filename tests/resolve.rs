@@ -0,0 +1,183 @@
+//! Runs the `ree` binary with its default flags against a nameserver stand-in on localhost.
+use serde_json::Value;
+use std::fs;
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::str::FromStr;
+use trust_dns_proto::op::{Message, MessageType, ResponseCode};
+use trust_dns_proto::rr::rdata::SOA;
+use trust_dns_proto::rr::{Name, RData, Record, RecordType};
+
+const WWW: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 1);
+const WILDCARD: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 99);
+
+fn name(name: &str) -> Name {
+    Name::from_str(name).unwrap()
+}
+
+/// Answers for `example.test`: `www` has an address, `api` is a CNAME to `www`, every name under
+/// `wild` is answered by a wildcard, and nothing else exists.
+fn answer(query: &Name, kind: RecordType) -> (ResponseCode, Vec<Record>) {
+    let host = query.to_utf8().to_lowercase();
+    let a = |owner: &Name, ip| Record::from_rdata(owner.clone(), 300, RData::A(ip));
+    let cname = Record::from_rdata(
+        name("api.example.test."),
+        300,
+        RData::CNAME(name("www.example.test.")),
+    );
+
+    match (host.as_str(), kind) {
+        ("www.example.test.", RecordType::A) => (ResponseCode::NoError, vec![a(query, WWW)]),
+        ("www.example.test.", _) => (ResponseCode::NoError, vec![]),
+        ("api.example.test.", RecordType::CNAME) => (ResponseCode::NoError, vec![cname]),
+        ("api.example.test.", RecordType::A) => (
+            ResponseCode::NoError,
+            vec![cname, a(&name("www.example.test."), WWW)],
+        ),
+        ("api.example.test.", _) => (ResponseCode::NoError, vec![cname]),
+        (host, RecordType::A) if host.ends_with(".wild.example.test.") => {
+            (ResponseCode::NoError, vec![a(query, WILDCARD)])
+        }
+        (host, _) if host.ends_with(".wild.example.test.") => (ResponseCode::NoError, vec![]),
+        _ => (ResponseCode::NXDomain, vec![]),
+    }
+}
+
+/// Starts the stand-in on a free port, and returns its address.
+fn nameserver() -> SocketAddr {
+    let socket = UdpSocket::bind(SocketAddr::from((Ipv4Addr::LOCALHOST, 0))).unwrap();
+    let addr = socket.local_addr().unwrap();
+    std::thread::spawn(move || {
+        let mut buf = [0; 4096];
+        loop {
+            let (len, from) = match socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(_) => continue,
+            };
+            let mut message = match Message::from_vec(&buf[..len]) {
+                Ok(message) => message,
+                Err(_) => continue,
+            };
+            let query = message.queries()[0].clone();
+            let (code, answers) = answer(query.name(), query.query_type());
+            let soa = Record::from_rdata(
+                name("example.test."),
+                300,
+                RData::SOA(SOA::new(
+                    name("ns.example.test."),
+                    name("admin.example.test."),
+                    1,
+                    3600,
+                    600,
+                    86400,
+                    300,
+                )),
+            );
+            message
+                .set_message_type(MessageType::Response)
+                .set_recursion_available(true)
+                .set_response_code(code)
+                .insert_answers(answers);
+            if message.answers().is_empty() {
+                message.insert_name_servers(vec![soa]);
+            }
+            let _ = socket.send_to(&message.to_vec().unwrap(), from);
+        }
+    });
+    addr
+}
+
+fn run(dir: &Path, hosts: &[&str], args: &[&str]) -> Vec<Value> {
+    let resolvers = dir.join("resolvers.txt");
+    fs::write(&resolvers, format!("{}\n", nameserver())).unwrap();
+    let input = dir.join("hosts.txt");
+    fs::write(&input, hosts.join("\n") + "\n").unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_ree"))
+        .arg("-i")
+        .arg(&input)
+        .arg("-r")
+        .arg(&resolvers)
+        .args(args)
+        .arg("-o")
+        .arg(dir.join("results"))
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "ree failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let results = fs::read_to_string(dir.join("results.json")).unwrap();
+    serde_json::from_str(&results).unwrap()
+}
+
+fn dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("ree-{}-{}", name, std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn addresses<'a>(results: &'a [Value], query: &str) -> Vec<(&'a str, bool)> {
+    results
+        .iter()
+        .filter(|r| r["query"] == query && r.get("ip").is_some_and(|ip| !ip.is_null()))
+        .map(|r| (r["ip"].as_str().unwrap(), r["is_wildcard"] == true))
+        .collect()
+}
+
+#[test]
+fn resolves_with_the_default_flags() {
+    let dir = dir("default");
+    let results = run(
+        &dir,
+        &[
+            "www.example.test",
+            "api.example.test",
+            "host.wild.example.test",
+            "missing.example.test",
+        ],
+        &[],
+    );
+
+    assert_eq!(
+        addresses(&results, "www.example.test."),
+        vec![("192.0.2.1", false)]
+    );
+    assert_eq!(
+        addresses(&results, "api.example.test."),
+        vec![("192.0.2.1", false)]
+    );
+    assert!(results.iter().any(|r| r["query"] == "api.example.test."
+        && r["type"] == "CNAME"
+        && r["name"] == "www.example.test."));
+    // The wildcard is found with the canaries, and its host is flagged.
+    assert_eq!(
+        addresses(&results, "host.wild.example.test."),
+        vec![("192.0.2.99", true)]
+    );
+    assert!(results
+        .iter()
+        .any(|r| r["query"] == "missing.example.test."
+            && r["response_code"] == "Non-Existent Domain"));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn drops_wildcards_found_with_the_canaries() {
+    let dir = dir("drop");
+    let results = run(
+        &dir,
+        &["www.example.test", "host.wild.example.test"],
+        &["--wildcard", "drop"],
+    );
+
+    assert_eq!(
+        addresses(&results, "www.example.test."),
+        vec![("192.0.2.1", false)]
+    );
+    assert!(addresses(&results, "host.wild.example.test.").is_empty());
+    fs::remove_dir_all(&dir).unwrap();
+}